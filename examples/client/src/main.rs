@@ -1,44 +1,37 @@
-use std::{env::args, time::Duration};
+use std::{collections::BTreeMap, env::args, time::Duration};
 
-use futures_util::StreamExt;
-use pluginx::client::{config::ClientConfig, ClientBuilder, StdioData};
+use pluginx::{
+    client::{
+        config::{ClientConfig, StdioSink},
+        ClientBuilder,
+    },
+    plugin::PluginSet,
+};
 use shared::{GetRequest, PutRequest};
-use tokio::{process::Command, select, time};
+use tokio::{process::Command, select};
 
 async fn amain() {
     let path = args().nth(1).expect("specify the plugin path");
 
     let builder = ClientBuilder::new(ClientConfig {
-        handshake_config: shared::HANDSHAKE_CONFIG,
-        cmd: Command::new(path),
-        broker_multiplex: false,
-        port_range: None,
-    });
-    let mut builder = time::timeout(Duration::from_secs(1), builder)
-        .await
-        .unwrap()
-        .unwrap();
-    builder.add_plugin(shared::KvPlugin).await;
-
-    let mut client = builder.build();
+        versioned_plugins: BTreeMap::from([(
+            shared::HANDSHAKE_CONFIG.protocol_version,
+            PluginSet::new().add_plugin(shared::KvPlugin),
+        )]),
+        auto_mtls: true,
+        start_timeout: Duration::from_secs(1),
+        sync_stdout: Some(StdioSink::callback(|x| {
+            println!("stdout: {}", String::from_utf8_lossy(x))
+        })),
+        sync_stderr: Some(StdioSink::callback(|x| {
+            println!("stderr: {}", String::from_utf8_lossy(x))
+        })),
+        ..ClientConfig::new(shared::HANDSHAKE_CONFIG, Command::new(path))
+    })
+    .await
+    .unwrap();
 
-    let stdio = client.stdio().unwrap();
-    tokio::spawn(async move {
-        let mut stdio = match stdio.read().await {
-            Ok(stdio) => stdio,
-            Err(e) => {
-                eprintln!("failed to read stdio: {e}");
-                return;
-            }
-        };
-        while let Some(msg) = stdio.next().await {
-            match msg {
-                StdioData::Stdout(x) => println!("stdout: {}", String::from_utf8_lossy(&x)),
-                StdioData::Stderr(x) => println!("stderr: {}", String::from_utf8_lossy(&x)),
-                _ => println!("invalid"),
-            }
-        }
-    });
+    let client = builder.build();
 
     let mut kv_client = client.dispense::<shared::KvPlugin>().unwrap();
 
@@ -64,7 +57,8 @@ async fn amain() {
         // ctrlc or infinity loop sleep 1s
         select! {
             _ = tokio::signal::ctrl_c() => {
-                client.shutdown().await;
+                let outcome = client.shutdown().await.unwrap();
+                println!("plugin stopped: {outcome:?}");
                 break;
             }
             _ = tokio::time::sleep(Duration::from_secs(1)) => {}
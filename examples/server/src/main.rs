@@ -31,11 +31,9 @@ impl shared::kv_server::Kv for KvImpl {
 }
 
 async fn amain() {
-    let mut server = Server::new(ServerConfig {
-        handshake_config: shared::HANDSHAKE_CONFIG,
-    })
-    .await
-    .unwrap();
+    let mut server = Server::new(ServerConfig::new(shared::HANDSHAKE_CONFIG))
+        .await
+        .unwrap();
 
     server
         .add_plugin(KvServer::new(KvImpl(Default::default())))
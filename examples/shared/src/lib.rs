@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use pluginx::{client::Channel, handshake::HandshakeConfig, plugin::PluginClient};
+use pluginx::{broker::Broker, client::Channel, handshake::HandshakeConfig, plugin::PluginClient};
 
 tonic::include_proto!("proto");
 
@@ -16,7 +16,7 @@ pub struct KvPlugin;
 impl PluginClient for KvPlugin {
     type Client = kv_client::KvClient<Channel>;
 
-    async fn client(&self, channel: Channel) -> Self::Client {
+    async fn client(&self, channel: Channel, _: Broker) -> Self::Client {
         kv_client::KvClient::new(channel)
     }
 }
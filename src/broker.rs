@@ -1,5 +1,336 @@
-/// on plugin caller side
-pub struct BrokerServer {}
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::Error as IoError,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-/// on plugin callee side
-pub struct BrokerClient {}
+use foldhash::{HashMap, HashMapExt};
+use futures_util::StreamExt;
+use thiserror::Error;
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot, watch,
+    },
+    time::{sleep, timeout},
+};
+use tonic::{service::Routes, transport::Channel, Streaming};
+
+use crate::{
+    common::{
+        client,
+        mux::Mux,
+        server::{self, Server as InnerServer, ServerConfig as InnerServerConfig, TransportConfig},
+        tls::TlsConfig,
+        utils::lock_ignore_poison,
+    },
+    handshake::Network,
+    proto::{conn_info::Knock, ConnInfo},
+    PluginxError,
+};
+
+/// How long connection info waits to be dialed, and how long [`Broker::dial`] waits for it.
+const CONN_INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum BrokerError {
+    #[error("broker is closed")]
+    Closed,
+
+    #[error("timeout waiting for connection info of service {0}")]
+    Timeout(u32),
+
+    #[error("failed to knock for service {id}: {error}")]
+    Knock { id: u32, error: String },
+}
+
+/// go-plugin's `GRPCBroker`, it lets the host and the plugin serve extra gRPC services to each
+/// other, e.g. a callback service passed from the host to a plugin.
+///
+/// One side reserves an id with [`Broker::next_id`] and serves on it with
+/// [`Broker::accept_and_serve`], the id is then passed to the other side (usually inside a
+/// request), which connects to the service with [`Broker::dial`].
+///
+/// If multiplexing was negotiated, services are served over the connection between host and
+/// plugin instead of new listeners.
+///
+/// The host gets it from [`Client::broker`](crate::client::Client::broker) and the plugin from
+/// [`Server::broker_handler`](crate::server::Server::broker_handler).
+#[derive(Clone)]
+pub struct Broker(Arc<Inner>);
+
+struct Inner {
+    next_id: AtomicU32,
+    transport_config: TransportConfig,
+    tls: Option<TlsConfig>,
+    mux: Option<Mux>,
+    /// connection info to send to the other side
+    outgoing: Sender<ConnInfo>,
+    /// connection info received from the other side, keyed by service id
+    pending: Mutex<HashMap<u32, Pending>>,
+    closed: watch::Sender<bool>,
+}
+
+enum Pending {
+    /// [`Broker::dial`] is waiting for it
+    Waiting(oneshot::Sender<ConnInfo>),
+    /// nobody dialed it yet, dropped after [`CONN_INFO_TIMEOUT`]
+    Arrived(ConnInfo, Instant),
+}
+
+impl Broker {
+    /// The returned receiver yields the connection info to send over the `StartStream` stream.
+    pub(crate) fn new(
+        transport_config: TransportConfig,
+        tls: Option<TlsConfig>,
+        mux: Option<Mux>,
+    ) -> (Self, Receiver<ConnInfo>) {
+        let (outgoing, rx) = mpsc::channel(1);
+
+        let inner = Inner {
+            next_id: AtomicU32::new(0),
+            transport_config,
+            tls,
+            mux,
+            outgoing,
+            pending: Mutex::new(HashMap::new()),
+            closed: watch::Sender::new(false),
+        };
+
+        (Self(Arc::new(inner)), rx)
+    }
+
+    /// Reserve an unused service id.
+    pub fn next_id(&self) -> u32 {
+        self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Listen for service `id`, tell the other side where to connect and serve `routes` there.
+    ///
+    /// Like go-plugin it only returns once the broker is closed or serving fails.
+    pub async fn accept_and_serve(&self, id: u32, routes: Routes) -> Result<(), PluginxError> {
+        let tls = self.0.tls.as_ref().map(TlsConfig::server_config);
+
+        // the other side knocks instead of waiting for connection info
+        if let Some(mux) = &self.0.mux {
+            let incoming = mux.listen(id).map(Ok).boxed();
+            return server::serve_incoming(incoming, tls, routes, self.closed()).await;
+        }
+
+        let server = InnerServer::new(InnerServerConfig {
+            transport_config: self.0.transport_config.clone(),
+            tls,
+            mux: None,
+        })
+        .await?;
+
+        let network = server.network();
+        self.send(ConnInfo {
+            service_id: id,
+            network: network.network_type().to_owned(),
+            address: network.address(),
+            knock: None,
+        })
+        .await?;
+
+        server.serve(routes, self.closed()).await
+    }
+
+    /// Connect to service `id` served by the other side.
+    ///
+    /// Waits up to 5 seconds for the other side to serve it. Dialing the same id concurrently
+    /// isn't supported, only the last call gets the connection.
+    pub async fn dial(&self, id: u32) -> Result<Channel, PluginxError> {
+        let tls = self.0.tls.as_ref().map(TlsConfig::client_config);
+
+        if let Some(mux) = self.0.mux.clone() {
+            let broker = self.clone();
+            // every connection of the channel, reconnections included, knocks first
+            let dialer = move || {
+                let (broker, mux) = (broker.clone(), mux.clone());
+                async move {
+                    broker.knock(id).await.map_err(IoError::other)?;
+                    mux.open().await
+                }
+            };
+            return client::connect_with(dialer, tls).await;
+        }
+
+        let info = self.conn_info(id).await?;
+        let network = Network::parse(&info.network, &info.address)?;
+
+        client::connect(network, tls).await
+    }
+
+    /// Stop serving all services and fail pending dials.
+    pub(crate) fn close(&self) {
+        self.0.closed.send_replace(true);
+        // dropping the senders wakes up the waiting dials
+        lock_ignore_poison(&self.0.pending).clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.0.closed.borrow()
+    }
+
+    /// resolves once the broker is closed
+    pub(crate) fn closed(&self) -> impl Future<Output = ()> + 'static {
+        let mut closed = self.0.closed.subscribe();
+        async move {
+            // the sender lives as long as the broker, which this future doesn't keep
+            _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
+
+    async fn send(&self, info: ConnInfo) -> Result<(), BrokerError> {
+        select! {
+            r = self.0.outgoing.send(info) => r.map_err(|_| BrokerError::Closed),
+            _ = self.closed() => Err(BrokerError::Closed),
+        }
+    }
+
+    /// Receive connection info from the other side until the stream ends, which closes the broker,
+    /// or the broker is closed.
+    pub(crate) async fn receive(&self, mut incoming: Streaming<ConnInfo>) {
+        let closed = self.closed();
+        let mut closed = pin!(closed);
+
+        loop {
+            let info = select! {
+                info = incoming.message() => info,
+                _ = &mut closed => break,
+            };
+            let Ok(Some(info)) = info else {
+                break;
+            };
+
+            match &info.knock {
+                Some(knock) if knock.knock && !knock.ack => {
+                    tokio::spawn(self.clone().answer_knock(info.service_id));
+                }
+                _ => self.deliver(info),
+            }
+        }
+
+        self.close();
+    }
+
+    /// Ask the other side to route the next stream to service `id`, go-plugin's knock.
+    async fn knock(&self, id: u32) -> Result<(), BrokerError> {
+        self.send(ConnInfo {
+            service_id: id,
+            knock: Some(Knock {
+                knock: true,
+                ack: false,
+                error: String::new(),
+            }),
+            ..Default::default()
+        })
+        .await?;
+
+        match self.conn_info(id).await?.knock {
+            Some(knock) if !knock.error.is_empty() => Err(BrokerError::Knock {
+                id,
+                error: knock.error,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn answer_knock(self, id: u32) {
+        let error = match &self.0.mux {
+            Some(mux) => mux
+                .accept_knock(id, CONN_INFO_TIMEOUT)
+                .await
+                .err()
+                .unwrap_or_default(),
+            None => "multiplexing is not enabled".to_owned(),
+        };
+
+        _ = self
+            .send(ConnInfo {
+                service_id: id,
+                knock: Some(Knock {
+                    knock: true,
+                    ack: true,
+                    error,
+                }),
+                ..Default::default()
+            })
+            .await;
+    }
+
+    fn deliver(&self, info: ConnInfo) {
+        let id = info.service_id;
+        let mut pending = lock_ignore_poison(&self.0.pending);
+
+        let info = match pending.remove(&id) {
+            Some(Pending::Waiting(tx)) => match tx.send(info) {
+                Ok(()) => return,
+                // the dial timed out meanwhile
+                Err(info) => info,
+            },
+            _ => info,
+        };
+
+        let arrived = Instant::now();
+        pending.insert(id, Pending::Arrived(info, arrived));
+        drop(pending);
+
+        let inner = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            sleep(CONN_INFO_TIMEOUT).await;
+
+            if let Some(inner) = inner.upgrade() {
+                let broker = Broker(inner);
+                let mut pending = lock_ignore_poison(&broker.0.pending);
+                if matches!(pending.get(&id), Some(Pending::Arrived(_, at)) if *at == arrived) {
+                    pending.remove(&id);
+                }
+            }
+        });
+    }
+
+    async fn conn_info(&self, id: u32) -> Result<ConnInfo, BrokerError> {
+        if self.is_closed() {
+            return Err(BrokerError::Closed);
+        }
+
+        let rx = {
+            let mut pending = lock_ignore_poison(&self.0.pending);
+            if let Some(Pending::Arrived(info, _)) = pending.remove(&id) {
+                return Ok(info);
+            }
+
+            let (tx, rx) = oneshot::channel();
+            pending.insert(id, Pending::Waiting(tx));
+            rx
+        };
+
+        match timeout(CONN_INFO_TIMEOUT, rx).await {
+            Ok(Ok(info)) => Ok(info),
+            Ok(Err(_)) => Err(BrokerError::Closed),
+            Err(_) => {
+                let mut pending = lock_ignore_poison(&self.0.pending);
+                if matches!(pending.get(&id), Some(Pending::Waiting(tx)) if tx.is_closed()) {
+                    pending.remove(&id);
+                }
+                Err(BrokerError::Timeout(id))
+            }
+        }
+    }
+}
+
+impl Debug for Broker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Broker")
+            .field("closed", &self.is_closed())
+            .finish_non_exhaustive()
+    }
+}
@@ -1,12 +1,378 @@
-use std::ops::RangeInclusive;
+use std::{
+    collections::BTreeMap, ops::RangeInclusive, path::PathBuf, pin::Pin, process::ExitStatus,
+    sync::Arc, time::Duration,
+};
 
-use tokio::process::Command;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    process::Command,
+    sync::Mutex,
+};
 
-use crate::handshake::HandshakeConfig;
+use crate::{handshake::HandshakeConfig, plugin::PluginSet};
 
 pub struct ClientConfig {
     pub handshake_config: HandshakeConfig<'static>,
     pub cmd: Command,
+    /// Plugins keyed by the app protocol version they speak. The plugin picks the highest version
+    /// it shares with the host, and only that set is dispensable.
+    ///
+    /// If empty, only [`HandshakeConfig::protocol_version`] is offered and plugins are expected
+    /// to be added with [`ClientBuilder::add_plugin`](super::ClientBuilder::add_plugin).
+    pub versioned_plugins: BTreeMap<u32, PluginSet>,
+    /// Multiplex broker connections over the connection to the plugin instead of opening new
+    /// listeners, go-plugin's `GRPCBrokerMultiplex`. Fails if the plugin doesn't support it.
     pub broker_multiplex: bool,
+    /// Generate ephemeral certificates and secure the connection with mutual TLS,
+    /// compatible with go-plugin's `AutoMTLS`.
+    pub auto_mtls: bool,
     pub port_range: Option<RangeInclusive<u16>>,
+    /// How long to wait for the plugin to print its handshake, go-plugin's `StartTimeout`.
+    pub start_timeout: Duration,
+    /// How [`Client::shutdown`](super::Client::shutdown) stops the plugin.
+    pub shutdown_policy: ShutdownPolicy,
+    /// Called from a background task as soon as the plugin exits, crashes as well as shutdowns.
+    pub on_exit: Option<ExitCallback>,
+    /// How a [`SupervisedClient`](super::SupervisedClient) restarts the plugin after it exited.
+    pub restart_policy: RestartPolicy,
+    /// Track the client so [`cleanup_all`](super::cleanup_all) stops its plugin, go-plugin's
+    /// `Managed`.
+    pub managed: bool,
+    /// Signal the plugin gets once the host dies, even by SIGKILL, with Linux's
+    /// `PR_SET_PDEATHSIG`. Ignored on other platforms.
+    ///
+    /// The kernel actually tracks the thread forking the plugin, so plugins are spawned from a
+    /// dedicated thread living as long as the host.
+    pub parent_death_signal: Option<i32>,
+    /// Spawn the plugin in its own process group and signal the whole group on shutdown, so
+    /// processes started by the plugin are stopped as well. The plugin no longer gets the
+    /// signals of the host's terminal, e.g. Ctrl-C.
+    pub process_group: bool,
+    /// Where the plugin stdout goes, go-plugin's `SyncStdout`. Both the stdio stream and the
+    /// raw pipe are forwarded there as soon as the plugin is connected, so
+    /// [`Client::stdio`](super::Client::stdio) and
+    /// [`Client::raw_stdout`](super::Client::raw_stdout) return [`None`].
+    ///
+    /// Setting either sink consumes the stdio stream, the output of the other one is discarded
+    /// from it unless set as well.
+    pub sync_stdout: Option<StdioSink>,
+    /// Where the plugin stderr goes, go-plugin's `SyncStderr`, like
+    /// [`sync_stdout`](Self::sync_stdout).
+    pub sync_stderr: Option<StdioSink>,
+    /// Verify the plugin executable before spawning it, go-plugin's `SecureConfig`.
+    pub secure_config: Option<SecureConfig>,
+    /// Restrict what the plugin can do, failing to spawn it if the sandbox can't be set up.
+    pub sandbox: Option<SandboxConfig>,
+}
+
+pub type ExitCallback = Arc<dyn Fn(ExitStatus) + Send + Sync>;
+
+pub type OutputCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Checks the plugin executable must pass before it is spawned.
+///
+/// The executable is verified once per config. On Linux the opened file itself is executed,
+/// restarts included, so replacing the path afterwards has no effect. It has to be a binary,
+/// scripts can't be executed that way, and the command must not use `env_clear`.
+#[derive(Clone, Debug, Default)]
+pub struct SecureConfig {
+    /// expected digest of the executable
+    pub checksum: Option<Checksum>,
+    /// Ed25519 public keys accepted for the detached signature next to the executable, at its
+    /// path with `.sig` appended. The signature is required unless empty.
+    ///
+    /// The signature file holds the 32 byte public key of the signer followed by the 64 byte
+    /// Ed25519ph signature of the whole executable, i.e. over its SHA-512 digest without
+    /// context, as made by `openssl pkeyutl -sign -rawin -pkeyopt instance:Ed25519ph`.
+    pub trusted_keys: Vec<[u8; 32]>,
+}
+
+/// Expected digest of the plugin executable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Sha256([u8; 32]),
+    Sha512([u8; 64]),
+}
+
+impl Checksum {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Sha256(digest) => digest,
+            Self::Sha512(digest) => digest,
+        }
+    }
+}
+
+/// Restrictions applied to the plugin process right before exec, on Linux only: spawning a
+/// sandboxed plugin fails on other platforms.
+///
+/// Setup failures in the plugin process, after forking, are reported by the kernel as the
+/// reason the plugin couldn't be spawned.
+#[derive(Clone, Debug, Default)]
+pub struct SandboxConfig {
+    /// Keep the plugin from gaining privileges, through setuid executables for instance.
+    /// Implied by [`landlock`](Self::landlock) and [`seccomp`](Self::seccomp).
+    pub no_new_privs: bool,
+    /// The filesystem access the plugin gets through Landlock, besides executing itself.
+    /// [`None`] leaves it unrestricted, rules on missing paths are ignored.
+    pub landlock: Option<Vec<FsRule>>,
+    pub seccomp: Option<SeccompFilter>,
+    pub namespaces: Namespaces,
+}
+
+impl SandboxConfig {
+    /// No new privileges and the [dangerous](SeccompFilter::dangerous) syscalls denied, which
+    /// plugins shouldn't notice.
+    #[cfg(target_os = "linux")]
+    pub fn baseline() -> Self {
+        Self {
+            no_new_privs: true,
+            seccomp: Some(SeccompFilter::dangerous()),
+            ..Self::default()
+        }
+    }
+
+    /// [`baseline`](Self::baseline), with read only access to the system directories,
+    /// read-write access to the temp dir of the host where the unix sockets are, and no
+    /// network, in new user and network namespaces.
+    #[cfg(target_os = "linux")]
+    pub fn strict() -> Self {
+        let system = ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"]
+            .map(|path| FsRule::new(path, FsAccess::ReadExecute));
+        let read = ["/etc", "/proc", "/dev/urandom"].map(|path| FsRule::new(path, FsAccess::Read));
+        let write = [
+            FsRule::new("/dev/null", FsAccess::ReadWrite),
+            FsRule::new(std::env::temp_dir(), FsAccess::ReadWrite),
+        ];
+
+        Self {
+            landlock: Some(system.into_iter().chain(read).chain(write).collect()),
+            namespaces: Namespaces {
+                user: true,
+                network: true,
+                mount: false,
+            },
+            ..Self::baseline()
+        }
+    }
+}
+
+/// Access to a file, or to everything beneath a directory.
+#[derive(Clone, Debug)]
+pub struct FsRule {
+    pub path: PathBuf,
+    pub access: FsAccess,
+}
+
+impl FsRule {
+    pub fn new(path: impl Into<PathBuf>, access: FsAccess) -> Self {
+        Self {
+            path: path.into(),
+            access,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsAccess {
+    Read,
+    ReadExecute,
+    /// reading, and creating, changing or removing anything but devices, without executing
+    ReadWrite,
+}
+
+/// A seccomp filter making the listed syscalls fail with `EPERM`. On x86_64, every syscall of
+/// the x32 ABI is denied as well.
+#[derive(Clone, Debug, Default)]
+pub struct SeccompFilter {
+    /// syscall numbers, `libc::SYS_*`
+    pub denied: Vec<libc::c_long>,
+}
+
+impl SeccompFilter {
+    /// Syscalls a plugin has no business making: tracing other processes, loading kernel
+    /// code, mounting, entering namespaces, changing the clock or the host name...
+    #[cfg(target_os = "linux")]
+    pub fn dangerous() -> Self {
+        Self {
+            denied: vec![
+                libc::SYS_ptrace,
+                libc::SYS_process_vm_readv,
+                libc::SYS_process_vm_writev,
+                libc::SYS_kexec_load,
+                libc::SYS_init_module,
+                libc::SYS_finit_module,
+                libc::SYS_delete_module,
+                libc::SYS_reboot,
+                libc::SYS_swapon,
+                libc::SYS_swapoff,
+                libc::SYS_mount,
+                libc::SYS_umount2,
+                libc::SYS_pivot_root,
+                libc::SYS_chroot,
+                libc::SYS_fsopen,
+                libc::SYS_fsmount,
+                libc::SYS_move_mount,
+                libc::SYS_open_tree,
+                libc::SYS_mount_setattr,
+                libc::SYS_setns,
+                libc::SYS_unshare,
+                libc::SYS_bpf,
+                libc::SYS_perf_event_open,
+                libc::SYS_userfaultfd,
+                libc::SYS_keyctl,
+                libc::SYS_add_key,
+                libc::SYS_request_key,
+                libc::SYS_acct,
+                libc::SYS_quotactl,
+                libc::SYS_open_by_handle_at,
+                libc::SYS_syslog,
+                libc::SYS_vhangup,
+                libc::SYS_settimeofday,
+                libc::SYS_clock_settime,
+                libc::SYS_clock_adjtime,
+                libc::SYS_adjtimex,
+                libc::SYS_sethostname,
+                libc::SYS_setdomainname,
+            ],
+        }
+    }
+}
+
+/// New namespaces the plugin is moved into.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Namespaces {
+    /// A user namespace mapping only the user and group of the host, which lets unprivileged
+    /// hosts create the other namespaces. Some distributions restrict them.
+    pub user: bool,
+    /// A network namespace without any interface up. The plugin must be reached through unix
+    /// sockets, as it is outside Windows.
+    pub network: bool,
+    /// A mount namespace, mounts made in there stay private to the plugin.
+    pub mount: bool,
+}
+
+/// Destination of plugin output, shared by the stdio stream, the raw pipe and restarts.
+#[derive(Clone)]
+pub enum StdioSink {
+    Writer(Arc<Mutex<Pin<Box<dyn AsyncWrite + Send>>>>),
+    /// called from a background task with every chunk of output
+    Callback(OutputCallback),
+}
+
+impl StdioSink {
+    pub fn writer(writer: impl AsyncWrite + Send + 'static) -> Self {
+        Self::Writer(Arc::new(Mutex::new(Box::pin(writer))))
+    }
+
+    pub fn callback(callback: impl Fn(&[u8]) + Send + Sync + 'static) -> Self {
+        Self::Callback(Arc::new(callback))
+    }
+
+    /// Parse hclog JSON lines, as written by go-plugin plugins, into [`tracing`] events at
+    /// their level, with their message, `@timestamp` and other keys as fields and tagged with
+    /// `plugin`. Other lines are logged at info.
+    ///
+    /// Meant for [`ClientConfig::sync_stderr`].
+    #[cfg(feature = "hclog")]
+    pub fn hclog(plugin: impl Into<String>) -> Self {
+        let parser = super::hclog::HclogParser::new(plugin.into());
+        Self::callback(move |data| parser.feed(data))
+    }
+
+    /// Write a whole chunk, flushing it so the output shows up timely. Failures are ignored,
+    /// there is nobody to report them to.
+    pub(crate) async fn write(&self, data: &[u8]) {
+        match self {
+            Self::Writer(writer) => {
+                let mut writer = writer.lock().await;
+                if writer.write_all(data).await.is_ok() {
+                    _ = writer.flush().await;
+                }
+            }
+            Self::Callback(callback) => callback(data),
+        }
+    }
+}
+
+/// Stages of [`Client::shutdown`](super::Client::shutdown), each one is tried once the previous
+/// one didn't stop the plugin within its timeout.
+#[derive(Clone, Debug)]
+pub struct ShutdownPolicy {
+    /// How long the plugin gets to exit after the Shutdown RPC, answered or not, should cover
+    /// the grace period of the plugin.
+    pub rpc_timeout: Duration,
+    /// How long the plugin gets to exit after SIGTERM.
+    pub terminate_timeout: Duration,
+    /// How long to wait for the plugin to exit after SIGKILL before giving up.
+    pub kill_timeout: Duration,
+}
+
+/// Restarts of a [`SupervisedClient`](super::SupervisedClient), delayed with exponential backoff.
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// Delay of the first restart, doubled for every following one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Restarts in a row before giving up, [`None`] to restart forever.
+    pub max_restarts: Option<u32>,
+    /// A plugin running at least this long resets the backoff and the restart count.
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: Some(5),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self {
+            rpc_timeout: Duration::from_secs(5),
+            terminate_timeout: Duration::from_secs(2),
+            kill_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub const DEFAULT_START_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// config with everything else set to its default
+    pub fn new(handshake_config: HandshakeConfig<'static>, cmd: Command) -> Self {
+        Self {
+            handshake_config,
+            cmd,
+            versioned_plugins: BTreeMap::new(),
+            broker_multiplex: false,
+            auto_mtls: false,
+            port_range: None,
+            start_timeout: Self::DEFAULT_START_TIMEOUT,
+            shutdown_policy: ShutdownPolicy::default(),
+            on_exit: None,
+            restart_policy: RestartPolicy::default(),
+            managed: false,
+            parent_death_signal: None,
+            process_group: false,
+            sync_stdout: None,
+            sync_stderr: None,
+            secure_config: None,
+            sandbox: None,
+        }
+    }
+
+    /// app protocol versions offered to the plugin
+    pub(crate) fn protocol_versions(&self) -> Vec<u32> {
+        if self.versioned_plugins.is_empty() {
+            vec![self.handshake_config.protocol_version]
+        } else {
+            self.versioned_plugins.keys().copied().collect()
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use std::{fmt::Write, sync::Mutex};
+
+use serde_json::{Map, Value};
+use tracing::{event, Level};
+
+use crate::{common::utils::lock_ignore_poison, meta_plugin::LineBuffer};
+
+const LEVEL_KEY: &str = "@level";
+const MESSAGE_KEY: &str = "@message";
+const TIMESTAMP_KEY: &str = "@timestamp";
+
+/// `event!` only takes a constant level.
+macro_rules! emit {
+    ($level:expr, $plugin:expr, $timestamp:expr, $fields:expr, $message:expr) => {
+        match $level {
+            Level::TRACE => emit!(@ Level::TRACE, $plugin, $timestamp, $fields, $message),
+            Level::DEBUG => emit!(@ Level::DEBUG, $plugin, $timestamp, $fields, $message),
+            Level::WARN => emit!(@ Level::WARN, $plugin, $timestamp, $fields, $message),
+            Level::ERROR => emit!(@ Level::ERROR, $plugin, $timestamp, $fields, $message),
+            _ => emit!(@ Level::INFO, $plugin, $timestamp, $fields, $message),
+        }
+    };
+    (@ $level:expr, $plugin:expr, $timestamp:expr, $fields:expr, $message:expr) => {
+        event!(
+            $level,
+            plugin = %$plugin,
+            timestamp = %$timestamp,
+            fields = %$fields,
+            "{}",
+            $message
+        )
+    };
+}
+
+/// Re-emits the hclog JSON lines a plugin writes to stderr as [`tracing`] events, like
+/// go-plugin does with the host logger.
+pub(crate) struct HclogParser {
+    plugin: String,
+    /// output not ended by a newline yet, bounded like the lines plugins write
+    line: Mutex<LineBuffer>,
+}
+
+impl HclogParser {
+    pub(crate) fn new(plugin: String) -> Self {
+        Self {
+            plugin,
+            line: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn feed(&self, data: &[u8]) {
+        // a line past the limit is emitted as it is, so a plugin never ending its line can't
+        // grow the buffer without bound
+        let Some(lines) = lock_ignore_poison(&self.line).push(data) else {
+            return;
+        };
+
+        for line in lines.split(|&b| b == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim_end_matches('\r');
+            if !line.is_empty() {
+                self.emit(line);
+            }
+        }
+    }
+
+    fn emit(&self, line: &str) {
+        let Ok(Value::Object(mut entry)) = serde_json::from_str(line) else {
+            // plain output, e.g. a panic message
+            emit!(Level::INFO, self.plugin, "", "", line);
+            return;
+        };
+
+        let level = match entry.remove(LEVEL_KEY) {
+            Some(Value::String(level)) => parse_level(&level),
+            _ => Level::INFO,
+        };
+        let message = match entry.remove(MESSAGE_KEY) {
+            Some(Value::String(message)) => message,
+            Some(message) => message.to_string(),
+            None => String::new(),
+        };
+        let timestamp = match entry.remove(TIMESTAMP_KEY) {
+            Some(Value::String(timestamp)) => timestamp,
+            _ => String::new(),
+        };
+        let fields = format_fields(&entry);
+
+        emit!(level, self.plugin, timestamp, fields, message);
+    }
+}
+
+/// hclog levels, unknown ones are logged at info
+fn parse_level(level: &str) -> Level {
+    match level.to_ascii_lowercase().as_str() {
+        "trace" => Level::TRACE,
+        "debug" => Level::DEBUG,
+        "warn" | "warning" => Level::WARN,
+        "error" => Level::ERROR,
+        _ => Level::INFO,
+    }
+}
+
+/// the remaining keys as `key=value` pairs, the way hclog prints them
+fn format_fields(entry: &Map<String, Value>) -> String {
+    let mut fields = String::new();
+
+    for (key, value) in entry {
+        if !fields.is_empty() {
+            fields.push(' ');
+        }
+        _ = match value {
+            Value::String(value) => write!(fields, "{key}={value}"),
+            value => write!(fields, "{key}={value}"),
+        };
+    }
+
+    fields
+}
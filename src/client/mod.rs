@@ -1,107 +1,512 @@
 pub mod config;
+#[cfg(feature = "hclog")]
+mod hclog;
+mod pipe;
+mod process;
+mod registry;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod secure;
+#[cfg(target_os = "linux")]
+mod spawner;
+mod supervisor;
+
+use std::{
+    future::pending,
+    io::{self, ErrorKind},
+    pin::pin,
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
 
-use std::{future::ready, process::Stdio};
-
-use futures_util::{Stream, StreamExt};
+use futures_util::{stream, Stream, StreamExt};
 use tokio::{
-    io::AsyncReadExt,
-    process::{Child, ChildStderr, ChildStdout},
+    io::{AsyncRead, AsyncReadExt},
+    process::{Child, ChildStderr, ChildStdout, Command},
+    select,
+    time::{sleep, timeout, timeout_at, Instant},
 };
 pub use tonic::transport::Channel;
-use tonic::Status;
+use tonic::Streaming;
 
-use self::config::ClientConfig;
+use self::config::{ClientConfig, ShutdownPolicy, StdioSink};
+pub use self::pipe::RawPipe;
+use self::process::{PluginProcess, ProcessHandle};
+use self::registry::Registration;
+pub use self::{registry::cleanup_all, supervisor::SupervisedClient};
 use crate::{
-    common::client::Client as InnerClient,
-    constant::{PLUGIN_MAX_PORT, PLUGIN_MIN_PORT},
-    handshake::{HandshakeError, HandshakeMessage},
-    meta_plugin::{ControllerClient, StdioClient},
+    broker::Broker,
+    common::{
+        client::Client as InnerClient,
+        mux::Mux,
+        server::TransportConfig,
+        tls::{TlsConfig, TlsIdentity},
+    },
+    constant::{
+        PLUGIN_CLIENT_CERT, PLUGIN_MAX_PORT, PLUGIN_MIN_PORT, PLUGIN_MULTIPLEX_GRPC,
+        PLUGIN_PROTOCOL_VERSIONS,
+    },
+    handshake::{HandshakeError, HandshakeMessage, CORE_PROTOCOL_VERSION},
+    meta_plugin::{BrokerClient, ControllerClient, StdioClient},
     plugin::PluginClient,
-    proto::stdio_data,
+    proto::{self, stdio_data},
     PluginxError,
 };
 
+/// Upper bound of the handshake line, it's a few hundred bytes even with a certificate inside.
+const MAX_HANDSHAKE_LEN: usize = 16 * 1024;
+
+/// How much of the plugin stderr is reported when it fails to start.
+const STDERR_TAIL_LIMIT: usize = 4 * 1024;
+
+/// How long a plugin that failed to start gets to exit and flush its stderr.
+const EXIT_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+/// How many times [`StdioStream`] tries to reopen the stream after losing it, and the delay
+/// before the first try, doubled for every following one.
+const STDIO_REOPEN_ATTEMPTS: u32 = 5;
+const STDIO_REOPEN_DELAY: Duration = Duration::from_millis(100);
+
+/// Prefix of the unix sockets served by the host's broker.
+const BROKER_UNIX_SOCKET_PREFIX: &str = "plugin-host-";
+
+/// Everything the plugin printed until its handshake line.
+struct StartupOutput {
+    handshake: Vec<u8>,
+    /// stdout printed right after the handshake line, arrived in the same read
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
 pub struct ClientBuilder {
-    plugin_host: Child,
+    plugin_host: PluginProcess,
+    protocol_version: u32,
+    stdout: Option<RawPipe<ChildStdout>>,
+    stderr: Option<RawPipe<ChildStderr>>,
+    shutdown_policy: ShutdownPolicy,
 
     controller: ControllerClient,
-    stdio: StdioClient,
+    stdio: Option<StdioClient>,
+    broker: Broker,
+    registration: Option<Registration>,
 
     client: InnerClient,
 }
 
 impl ClientBuilder {
     pub async fn new(mut config: ClientConfig) -> Result<Self, PluginxError> {
+        Self::configure(&mut config).await?;
+        Self::spawn(&mut config).await
+    }
+
+    /// Apply the process options of `config` to its command, sandboxing and verifying the
+    /// executable if asked.
+    /// Only once per config, since the hooks run before exec add up.
+    pub(crate) async fn configure(config: &mut ClientConfig) -> Result<(), PluginxError> {
+        if config.process_group {
+            config.cmd.process_group(0);
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(signal) = config.parent_death_signal {
+            let host = std::process::id() as libc::pid_t;
+
+            // SAFETY: only async-signal-safe functions are called between fork and exec
+            unsafe {
+                config.cmd.pre_exec(move || {
+                    if libc::prctl(libc::PR_SET_PDEATHSIG, signal as libc::c_ulong) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // the host died before the signal was armed
+                    if libc::getppid() != host {
+                        return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(sandbox) = &config.sandbox {
+            sandbox::apply(&mut config.cmd, sandbox).map_err(PluginxError::Sandbox)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.sandbox.is_some() {
+            return Err(PluginxError::Sandbox(io::Error::new(
+                ErrorKind::Unsupported,
+                "sandboxing is only supported on linux",
+            )));
+        }
+
+        if let Some(secure) = &config.secure_config {
+            let program = config.cmd.as_std().get_program().to_owned();
+            let _file = secure::verify(&program, secure.clone()).await?;
+
+            // the hook executing it must be the last one
+            #[cfg(target_os = "linux")]
+            secure::exec_verified(&mut config.cmd, _file)?;
+        }
+
+        Ok(())
+    }
+
+    /// Start a plugin instance, `config` is kept for restarts.
+    pub(crate) async fn spawn(config: &mut ClientConfig) -> Result<Self, PluginxError> {
         // 1. build plugin env
         let port_range = config.port_range.clone().unwrap_or(10000..=25000);
         let (magic_key, magic_value) = (
             config.handshake_config.magic_cookie_key.as_ref(),
             config.handshake_config.magic_cookie_value.as_ref(),
         );
+        let identity = config.auto_mtls.then(TlsIdentity::generate).transpose()?;
+        let protocol_versions: Vec<_> = config
+            .protocol_versions()
+            .iter()
+            .map(u32::to_string)
+            .collect();
 
         // 2. spawn plugin process
-        let mut plugin_host = config
+        config
             .cmd
             .envs([
                 (magic_key, magic_value),
                 (PLUGIN_MIN_PORT, &port_range.start().to_string()),
                 (PLUGIN_MAX_PORT, &port_range.end().to_string()),
+                (PLUGIN_PROTOCOL_VERSIONS, &protocol_versions.join(",")),
                 // TODO: unix socket dir
             ])
+            // go plugins advertise multiplexing whenever it's set, whatever the value
+            .envs(
+                config
+                    .broker_multiplex
+                    .then_some((PLUGIN_MULTIPLEX_GRPC, "true")),
+            )
+            .envs(
+                identity
+                    .as_ref()
+                    .map(|i| (PLUGIN_CLIENT_CERT, i.cert_pem())),
+            )
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()?;
-
-        // 3. wait for handshake
-        let stdout = plugin_host
-            .stdout
-            .as_mut()
-            .expect("stdout is pipe, must success");
-        let mut buf = Vec::new();
-
-        stdout
-            .read_buf(&mut buf)
-            .await
-            .map_err(|_| HandshakeError::InvalidHandshakeMessage)?;
-
-        let stdout = String::from_utf8_lossy(&buf);
-
-        let handshake =
-            HandshakeMessage::parse(stdout.trim()).map_err(|error| PluginxError::Handshake {
-                error,
-                message: stdout.to_string(),
-            })?;
-
-        // 4. connect with gRPC
-        let client = InnerClient::new(handshake.network.clone()).await?;
+            .kill_on_drop(true);
+
+        #[cfg(target_os = "linux")]
+        let plugin_host = {
+            let exec = match config.secure_config {
+                Some(_) => secure::spawn,
+                None => Command::spawn,
+            };
+            let sandboxed = config.sandbox.is_some();
+            let spawn = move |cmd: &mut Command| {
+                if sandboxed {
+                    sandbox::spawn(cmd, exec)
+                } else {
+                    Ok(exec(cmd)?)
+                }
+            };
+            match config.parent_death_signal {
+                Some(_) => spawner::spawn(&mut config.cmd, spawn).await,
+                None => spawn(&mut config.cmd),
+            }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let plugin_host = config.cmd.spawn().map_err(PluginxError::from);
+        let mut plugin_host = plugin_host?;
+
+        // services served by the host are reached the same way the plugin is
+        let transport_config = if cfg!(windows) {
+            TransportConfig::Tcp { port_range }
+        } else {
+            TransportConfig::Unix {
+                prefix: BROKER_UNIX_SOCKET_PREFIX.into(),
+                dir: None,
+            }
+        };
+
+        let connected = async {
+            // 3. wait for handshake
+            let output = Self::wait_handshake(&mut plugin_host, config.start_timeout).await?;
+
+            let stdout = String::from_utf8_lossy(&output.handshake);
+
+            let handshake = HandshakeMessage::parse(stdout.trim())
+                .and_then(|handshake| Self::check_protocol_version(config, handshake))
+                .and_then(|handshake| {
+                    if config.broker_multiplex && handshake.multiplex != Some(true) {
+                        return Err(HandshakeError::MultiplexNotSupported);
+                    }
+                    Ok(handshake)
+                })
+                .map_err(|error| PluginxError::Handshake {
+                    error,
+                    message: stdout.to_string(),
+                })?;
+
+            // 4. connect with gRPC, pinning the plugin certificate if AutoMTLS is enabled
+            let tls = match (identity, handshake.server_cert) {
+                (Some(identity), Some(cert)) => Some(TlsConfig::new(identity, cert.into())?),
+                (Some(_), None) => Err(HandshakeError::MissingServerCert)?,
+                (None, _) => None,
+            };
+            let mux = config.broker_multiplex.then(Mux::default);
+            let mut client = InnerClient::new(
+                handshake.network.clone(),
+                mux.clone(),
+                tls.as_ref().map(TlsConfig::client_config),
+            )
+            .await?;
+
+            // the plugins may need the broker to serve callbacks
+            let (broker, outgoing) = Broker::new(transport_config, tls, mux);
+            BrokerClient::new(client.channel().clone()).start(broker.clone(), outgoing);
+
+            // 5. load plugins of the negotiated version, the plugin picked one of the offered
+            if let Some(plugins) = config.versioned_plugins.get(&handshake.app_protocol) {
+                plugins.register(&mut client, &broker).await;
+            }
+
+            Ok::<_, PluginxError>((handshake.app_protocol, output, client, broker))
+        }
+        .await;
+
+        let (protocol_version, output, client, broker) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                // nobody is able to talk with this plugin, don't leave it running
+                _ = plugin_host.kill().await;
+                return Err(e);
+            }
+        };
+
+        let stdout = RawPipe::new(
+            output.stdout,
+            plugin_host
+                .stdout
+                .take()
+                .expect("stdout is pipe, must success"),
+        );
+        let stderr = RawPipe::new(
+            output.stderr,
+            plugin_host
+                .stderr
+                .take()
+                .expect("stderr is pipe, must success"),
+        );
+        let plugin_host =
+            PluginProcess::spawn(plugin_host, config.process_group, config.on_exit.clone());
 
-        // 5. load builtin plugins
+        // 6. load builtin plugins
         let controller = ControllerClient::new(client.channel().clone());
-        let stdio = StdioClient::new(client.channel().clone());
+        let mut stdio = Some(StdioClient::new(client.channel().clone()));
+
+        // 7. forward the output to the configured sinks
+        let (sync_stdout, sync_stderr) = (&config.sync_stdout, &config.sync_stderr);
+        if sync_stdout.is_some() || sync_stderr.is_some() {
+            let stdio = StdioStream(stdio.take().expect("stdio is just created"));
+            tokio::spawn(forward_stdio(
+                stdio,
+                sync_stdout.clone(),
+                sync_stderr.clone(),
+            ));
+        }
+        let stdout = Self::forward_pipe(stdout, sync_stdout);
+        let stderr = Self::forward_pipe(stderr, sync_stderr);
+
+        let registration = config.managed.then(|| {
+            Registration::register(
+                controller.clone(),
+                plugin_host.handle(),
+                broker.clone(),
+                config.shutdown_policy.clone(),
+            )
+        });
 
         Ok(Self {
             plugin_host,
+            protocol_version,
+            stdout,
+            stderr,
+            shutdown_policy: config.shutdown_policy.clone(),
 
             controller,
             stdio,
+            broker,
+            registration,
 
             client,
         })
     }
 
+    /// Forward `pipe` into `sink` if any, otherwise keep it for the user.
+    fn forward_pipe<R: AsyncRead + Unpin + Send + 'static>(
+        mut pipe: RawPipe<R>,
+        sink: &Option<StdioSink>,
+    ) -> Option<RawPipe<R>> {
+        let Some(sink) = sink.clone() else {
+            return Some(pipe);
+        };
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 4096];
+            while let Ok(n @ 1..) = pipe.read(&mut buf).await {
+                sink.write(&buf[..n]).await;
+            }
+        });
+
+        None
+    }
+
+    /// Read exactly one handshake line within `start_timeout`, keeping whatever else the plugin
+    /// printed meanwhile.
+    async fn wait_handshake(
+        plugin_host: &mut Child,
+        start_timeout: Duration,
+    ) -> Result<StartupOutput, PluginxError> {
+        let stdout = plugin_host
+            .stdout
+            .as_mut()
+            .expect("stdout is pipe, must success");
+        let stderr = plugin_host
+            .stderr
+            .as_mut()
+            .expect("stderr is pipe, must success");
+        let mut stderr_buf = Vec::new();
+
+        let read_handshake = async {
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+
+            // the line may arrive in pieces (it carries a whole certificate with AutoMTLS),
+            // or along with the output following it
+            loop {
+                let n = stdout
+                    .read(&mut chunk)
+                    .await
+                    .map_err(|_| HandshakeError::InvalidHandshakeMessage)?;
+                if n == 0 {
+                    return Err(HandshakeError::PluginExited);
+                }
+
+                let searched = buf.len();
+                buf.extend_from_slice(&chunk[..n]);
+
+                if let Some(i) = buf[searched..].iter().position(|&b| b == b'\n') {
+                    let rest = buf.split_off(searched + i + 1);
+                    buf.pop();
+                    return Ok((buf, rest));
+                }
+
+                if buf.len() > MAX_HANDSHAKE_LEN {
+                    return Err(HandshakeError::HandshakeMessageTooLong(MAX_HANDSHAKE_LEN));
+                }
+            }
+        };
+
+        // a plugin logging a lot before the handshake would block on a full pipe otherwise
+        let drain_stderr = async {
+            loop {
+                match stderr.read_buf(&mut stderr_buf).await {
+                    Ok(0) | Err(_) => pending::<()>().await,
+                    Ok(_) => {}
+                }
+            }
+        };
+
+        let r = select! {
+            r = timeout(start_timeout, read_handshake) => r,
+            _ = drain_stderr => unreachable!("draining stderr never ends"),
+        };
+
+        let error = match r {
+            Ok(Ok((handshake, stdout))) => {
+                return Ok(StartupOutput {
+                    handshake,
+                    stdout,
+                    stderr: stderr_buf,
+                })
+            }
+            Ok(Err(e)) => e,
+            Err(_) => HandshakeError::Timeout(start_timeout),
+        };
+
+        Err(Self::start_failure(plugin_host, stderr_buf, error).await)
+    }
+
+    /// Collect the exit status and the stderr tail of a plugin that never came up.
+    async fn start_failure(
+        plugin_host: &mut Child,
+        mut stderr_buf: Vec<u8>,
+        error: HandshakeError,
+    ) -> PluginxError {
+        let status = match timeout(EXIT_GRACE_PERIOD, plugin_host.wait()).await {
+            Ok(status) => status.ok(),
+            Err(_) => {
+                _ = plugin_host.start_kill();
+                plugin_host.wait().await.ok()
+            }
+        };
+
+        // children of the plugin may hold the pipe open, don't wait for them
+        if let Some(stderr) = plugin_host.stderr.as_mut() {
+            _ = timeout(EXIT_GRACE_PERIOD, stderr.read_to_end(&mut stderr_buf)).await;
+        }
+
+        let tail = &stderr_buf[stderr_buf.len().saturating_sub(STDERR_TAIL_LIMIT)..];
+
+        PluginxError::Start {
+            error,
+            status,
+            stderr: String::from_utf8_lossy(tail).into_owned(),
+        }
+    }
+
+    fn check_protocol_version(
+        config: &ClientConfig,
+        handshake: HandshakeMessage,
+    ) -> Result<HandshakeMessage, HandshakeError> {
+        if handshake.core_protocol != CORE_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedCoreProtocolVersion {
+                expected: CORE_PROTOCOL_VERSION,
+                received: handshake.core_protocol,
+            });
+        }
+
+        let expected = config.protocol_versions();
+        if !expected.contains(&handshake.app_protocol) {
+            return Err(HandshakeError::UnsupportedAppProtocolVersion {
+                expected,
+                received: handshake.app_protocol,
+            });
+        }
+
+        Ok(handshake)
+    }
+
     pub async fn add_plugin<P: PluginClient + 'static>(&mut self, plugin: P) -> &mut Self {
-        let plugin = plugin.client(self.client.channel().clone()).await;
+        let plugin = plugin
+            .client(self.client.channel().clone(), self.broker.clone())
+            .await;
         self.client.add_service(plugin);
         self
     }
 
+    /// app protocol version negotiated with the plugin
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
     pub fn build(self) -> Client {
         Client {
             plugin_host: self.plugin_host,
+            protocol_version: self.protocol_version,
+            stdout: self.stdout,
+            stderr: self.stderr,
+            shutdown_policy: self.shutdown_policy,
 
             controller: self.controller,
-            stdio: Some(self.stdio),
+            stdio: self.stdio,
+            broker: self.broker,
+            _registration: self.registration,
 
             client: self.client,
         }
@@ -116,49 +521,151 @@ pub enum StdioData {
     Stderr(Vec<u8>),
 }
 
+/// The [`ShutdownPolicy`] stage that stopped the plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownStage {
+    /// the plugin had exited before shutting it down
+    Exited,
+    Rpc,
+    Terminate,
+    Kill,
+}
+
+/// How the plugin stopped, returned by [`Client::shutdown`].
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownOutcome {
+    pub stage: ShutdownStage,
+    pub status: ExitStatus,
+}
+
 pub struct Client {
-    plugin_host: Child,
+    plugin_host: PluginProcess,
+    protocol_version: u32,
+    stdout: Option<RawPipe<ChildStdout>>,
+    stderr: Option<RawPipe<ChildStderr>>,
+    shutdown_policy: ShutdownPolicy,
 
     controller: ControllerClient,
     stdio: Option<StdioClient>,
+    broker: Broker,
+    /// unregistered when the client is dropped, shut down or not
+    _registration: Option<Registration>,
 
     client: InnerClient,
 }
 
 impl Client {
+    /// app protocol version negotiated with the plugin
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
     pub fn dispense<P: PluginClient + 'static>(&self) -> Option<P::Client> {
         self.client.dispense::<P::Client>()
     }
 
+    /// exit status of the plugin if it has exited, without blocking
+    pub fn is_exited(&self) -> Option<ExitStatus> {
+        self.plugin_host.try_status()
+    }
+
+    /// Resolves with the exit status once the plugin exits, for whatever reason. The future
+    /// doesn't borrow the client, so it can be awaited in a separate task.
+    pub fn exited(&self) -> impl Future<Output = ExitStatus> + Send + 'static {
+        self.plugin_host.exited()
+    }
+
+    /// broker to serve services to the plugin, or to connect to the ones served by it
+    pub fn broker(&self) -> Broker {
+        self.broker.clone()
+    }
+
     /// stdout/stderr data sent from plugin host, it can be only called once, or it will return [`None`].
     pub fn stdio(&mut self) -> Option<StdioStream> {
         self.stdio.take().map(StdioStream)
     }
 
     /// raw stdout from process instead of RPC, can only be called once, or it will return [`None`].
-    pub fn raw_stdout(&mut self) -> Option<ChildStdout> {
-        self.plugin_host.stdout.take()
+    pub fn raw_stdout(&mut self) -> Option<RawPipe<ChildStdout>> {
+        self.stdout.take()
     }
 
     /// raw stderr from process instead of RPC, can only be called once, or it will return [`None`].
-    pub fn raw_stderr(&mut self) -> Option<ChildStderr> {
-        self.plugin_host.stderr.take()
+    pub fn raw_stderr(&mut self) -> Option<RawPipe<ChildStderr>> {
+        self.stderr.take()
     }
 
-    pub async fn shutdown(mut self) {
-        _ = self.controller.shutdown().await;
-        _ = self.plugin_host.wait().await;
+    /// Stop the plugin following [`ClientConfig::shutdown_policy`]: the Shutdown RPC first,
+    /// then SIGTERM and SIGKILL, each once the previous stage timed out.
+    ///
+    /// Fails only if the plugin survives SIGKILL within its timeout.
+    pub async fn shutdown(mut self) -> Result<ShutdownOutcome, PluginxError> {
+        self.broker.close();
+        stop(
+            &mut self.controller,
+            &self.plugin_host,
+            &self.shutdown_policy,
+        )
+        .await
+    }
+}
+
+/// The stages of [`Client::shutdown`], shared with [`cleanup_all`].
+async fn stop(
+    controller: &mut ControllerClient,
+    process: &ProcessHandle,
+    policy: &ShutdownPolicy,
+) -> Result<ShutdownOutcome, PluginxError> {
+    if let Some(status) = process.try_status() {
+        return Ok(ShutdownOutcome {
+            stage: ShutdownStage::Exited,
+            status,
+        });
+    }
+
+    // 1. ask the plugin to exit, the RPC shares the timeout with the exit. Its result doesn't
+    // matter, go-plugin servers stop hard and usually cut the reply off while exiting
+    let deadline = Instant::now() + policy.rpc_timeout;
+    _ = timeout_at(deadline, controller.shutdown()).await;
+    if let Ok(status) = timeout_at(deadline, process.exited()).await {
+        return Ok(ShutdownOutcome {
+            stage: ShutdownStage::Rpc,
+            status,
+        });
+    }
+
+    // 2. SIGTERM, unless the plugin exited for the RPC right after its timeout
+    if let Some(status) = process.terminate().await {
+        return Ok(ShutdownOutcome {
+            stage: ShutdownStage::Rpc,
+            status,
+        });
+    }
+    if let Ok(status) = timeout(policy.terminate_timeout, process.exited()).await {
+        return Ok(ShutdownOutcome {
+            stage: ShutdownStage::Terminate,
+            status,
+        });
+    }
+
+    // 3. SIGKILL
+    process.kill();
+    match timeout(policy.kill_timeout, process.exited()).await {
+        Ok(status) => Ok(ShutdownOutcome {
+            stage: ShutdownStage::Kill,
+            status,
+        }),
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "plugin didn't exit after SIGKILL",
+        ))?,
     }
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        // if process is still running, kill it
-        if let Ok(None) = self.plugin_host.try_wait() {
-            _ = self.plugin_host.start_kill();
-            // prevent zombie process
-            _ = self.plugin_host.try_wait();
-        }
+        // the plugin process is killed by its own drop
+        self.broker.close();
     }
 }
 
@@ -167,15 +674,81 @@ impl Drop for Client {
 pub struct StdioStream(StdioClient);
 
 impl StdioStream {
-    pub async fn read(mut self) -> Result<impl Stream<Item = StdioData>, Status> {
-        let s = self.0.read().await?;
-
-        Ok(s.take_while(|x| ready(Result::is_ok(x)))
-            .map(|x| x.expect("x must be Ok"))
-            .map(|x| match x.channel() {
-                stdio_data::Channel::Invalid => StdioData::Invalid,
-                stdio_data::Channel::Stdout => StdioData::Stdout(x.data),
-                stdio_data::Channel::Stderr => StdioData::Stderr(x.data),
-            }))
+    /// Open the stream of the plugin output.
+    ///
+    /// It ends once the plugin closes it, e.g. while exiting. If it breaks instead, the error is
+    /// yielded as [`PluginxError::Stdio`] and the stream is reopened, receiving the output the
+    /// plugin kept meanwhile. It ends right after an error if reopening keeps failing.
+    ///
+    /// The stream belongs to one plugin instance, use [`ClientConfig::sync_stdout`] and
+    /// [`ClientConfig::sync_stderr`] to follow the restarts of a [`SupervisedClient`].
+    pub async fn read(
+        mut self,
+    ) -> Result<impl Stream<Item = Result<StdioData, PluginxError>>, PluginxError> {
+        let streaming = self.0.read().await.map_err(PluginxError::Stdio)?;
+
+        Ok(stream::unfold(
+            (self.0, Some(streaming)),
+            |(mut client, mut streaming)| async move {
+                loop {
+                    let Some(s) = &mut streaming else {
+                        streaming = Some(Self::reopen(&mut client).await?);
+                        continue;
+                    };
+
+                    return match s.message().await {
+                        Ok(Some(data)) => Some((Ok(data.into()), (client, streaming))),
+                        Ok(None) => None,
+                        Err(status) => Some((Err(PluginxError::Stdio(status)), (client, None))),
+                    };
+                }
+            },
+        ))
+    }
+
+    async fn reopen(client: &mut StdioClient) -> Option<Streaming<proto::StdioData>> {
+        let mut delay = STDIO_REOPEN_DELAY;
+
+        // the plugin gets the stream back only once it notices the broken one
+        for _ in 0..STDIO_REOPEN_ATTEMPTS {
+            sleep(delay).await;
+            if let Ok(streaming) = client.read().await {
+                return Some(streaming);
+            }
+            delay *= 2;
+        }
+
+        None
+    }
+}
+
+impl From<proto::StdioData> for StdioData {
+    fn from(data: proto::StdioData) -> Self {
+        match data.channel() {
+            stdio_data::Channel::Invalid => Self::Invalid,
+            stdio_data::Channel::Stdout => Self::Stdout(data.data),
+            stdio_data::Channel::Stderr => Self::Stderr(data.data),
+        }
+    }
+}
+
+/// Forward the stdio stream to the sinks of its channels until it ends.
+async fn forward_stdio(stdio: StdioStream, stdout: Option<StdioSink>, stderr: Option<StdioSink>) {
+    let Ok(stream) = stdio.read().await else {
+        return;
+    };
+    let mut stream = pin!(stream);
+
+    // errors are followed by reopening the stream, or its end
+    while let Some(data) = stream.next().await {
+        let (sink, data) = match data {
+            Ok(StdioData::Stdout(data)) => (&stdout, data),
+            Ok(StdioData::Stderr(data)) => (&stderr, data),
+            Ok(StdioData::Invalid) | Err(_) => continue,
+        };
+
+        if let Some(sink) = sink {
+            sink.write(&data).await;
+        }
     }
 }
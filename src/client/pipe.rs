@@ -0,0 +1,49 @@
+use std::{
+    io::Result as IoResult,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Raw stdout/stderr pipe of the plugin process.
+///
+/// Output already consumed by pluginx while starting the plugin is yielded first, so nothing
+/// written by the plugin gets lost.
+#[derive(Debug)]
+pub struct RawPipe<R> {
+    buffered: Bytes,
+    inner: R,
+}
+
+impl<R> RawPipe<R> {
+    pub(crate) fn new(buffered: impl Into<Bytes>, inner: R) -> Self {
+        Self {
+            buffered: buffered.into(),
+            inner,
+        }
+    }
+
+    /// split into the output read during startup and the underlying pipe
+    pub fn into_parts(self) -> (Bytes, R) {
+        (self.buffered, self.inner)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RawPipe<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        if self.buffered.has_remaining() {
+            let n = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..n]);
+            self.buffered.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
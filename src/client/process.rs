@@ -0,0 +1,145 @@
+use std::{future::pending, ops::Deref, process::ExitStatus, time::Duration};
+
+use tokio::{
+    process::Child,
+    select,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    time::sleep,
+};
+
+use super::config::ExitCallback;
+
+enum Signal {
+    /// answered with the exit status instead if the plugin already exited
+    Terminate(oneshot::Sender<Option<ExitStatus>>),
+    Kill,
+}
+
+/// The plugin process, waited on in background so its exit is observed as soon as it happens.
+///
+/// Only the background task touches the [`Child`], which makes signalling it race free: once it
+/// is reaped, its pid may belong to another process.
+pub(crate) struct PluginProcess(ProcessHandle);
+
+/// Observes and signals a [`PluginProcess`] without owning it.
+#[derive(Clone)]
+pub(crate) struct ProcessHandle {
+    exit: watch::Receiver<Option<ExitStatus>>,
+    signals: UnboundedSender<Signal>,
+}
+
+impl PluginProcess {
+    /// `group` signals the process group led by `child` instead of `child` alone.
+    pub(crate) fn spawn(child: Child, group: bool, on_exit: Option<ExitCallback>) -> Self {
+        let (exit_tx, exit) = watch::channel(None);
+        let (signals, signals_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::wait(child, group, signals_rx, exit_tx, on_exit));
+
+        Self(ProcessHandle { exit, signals })
+    }
+
+    pub(crate) fn handle(&self) -> ProcessHandle {
+        self.0.clone()
+    }
+
+    async fn wait(
+        mut child: Child,
+        group: bool,
+        mut signals: UnboundedReceiver<Signal>,
+        exit: watch::Sender<Option<ExitStatus>>,
+        on_exit: Option<ExitCallback>,
+    ) {
+        let status = loop {
+            select! {
+                status = child.wait() => match status {
+                    Ok(status) => break status,
+                    // waitpid failures are transient in practice
+                    Err(_) => sleep(Duration::from_millis(100)).await,
+                },
+                Some(signal) = signals.recv() => match signal {
+                    Signal::Terminate(reply) => {
+                        // exited but not reaped yet, e.g. right as the Shutdown RPC timed out
+                        if let Ok(Some(status)) = child.try_wait() {
+                            _ = reply.send(Some(status));
+                            break status;
+                        }
+                        Self::signal(&child, group, libc::SIGTERM);
+                        _ = reply.send(None);
+                    }
+                    Signal::Kill => Self::signal(&child, group, libc::SIGKILL),
+                }
+            }
+        };
+
+        exit.send_replace(Some(status));
+
+        if let Some(on_exit) = on_exit {
+            on_exit(status);
+        }
+    }
+
+    fn signal(child: &Child, group: bool, signal: libc::c_int) {
+        // not reaped yet, as long as `id` returns the pid
+        let Some(pid) = child.id() else {
+            return;
+        };
+
+        // the id of a process group is the pid of its leader
+        let pid = pid as libc::pid_t;
+        let target = if group { -pid } else { pid };
+
+        // SAFETY: kill(2) has no memory safety requirements
+        unsafe { libc::kill(target, signal) };
+    }
+}
+
+impl Deref for PluginProcess {
+    type Target = ProcessHandle;
+
+    fn deref(&self) -> &ProcessHandle {
+        &self.0
+    }
+}
+
+impl ProcessHandle {
+    pub(crate) fn try_status(&self) -> Option<ExitStatus> {
+        *self.exit.borrow()
+    }
+
+    pub(crate) fn exited(&self) -> impl Future<Output = ExitStatus> + Send + 'static {
+        let mut exit = self.exit.clone();
+
+        async move {
+            let status = exit.wait_for(Option::is_some).await.map(|status| *status);
+            match status {
+                Ok(status) => status.expect("waited for Some"),
+                // the runtime is shutting down, the status will never be known
+                Err(_) => pending().await,
+            }
+        }
+    }
+
+    /// Send SIGTERM, unless the plugin already exited, whose status is returned then.
+    pub(crate) async fn terminate(&self) -> Option<ExitStatus> {
+        let (reply, replied) = oneshot::channel();
+        _ = self.signals.send(Signal::Terminate(reply));
+
+        // the task is gone once the plugin is reaped, after publishing the status
+        replied.await.unwrap_or_else(|_| self.try_status())
+    }
+
+    pub(crate) fn kill(&self) {
+        _ = self.signals.send(Signal::Kill);
+    }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        // nobody is able to talk with the plugin anymore
+        self.kill();
+    }
+}
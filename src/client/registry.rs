@@ -0,0 +1,135 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, ErrorKind},
+    mem,
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures_util::future::join_all;
+use tokio::time::timeout as timeout_after;
+
+use super::{config::ShutdownPolicy, process::ProcessHandle, stop};
+use crate::{
+    broker::Broker, common::utils::lock_ignore_poison, meta_plugin::ControllerClient, PluginxError,
+};
+
+/// Clients created with [`ClientConfig::managed`](super::config::ClientConfig::managed), go-plugin
+/// keeps the same list for `CleanupClients`.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    clients: BTreeMap::new(),
+    supervisors: BTreeSet::new(),
+});
+
+struct Registry {
+    next_id: u64,
+    clients: BTreeMap<u64, Managed>,
+    /// [`SupervisedClient`](super::SupervisedClient)s allowed to restart their plugin
+    supervisors: BTreeSet<u64>,
+}
+
+/// Everything needed to stop a plugin without its [`Client`](super::Client).
+struct Managed {
+    controller: ControllerClient,
+    process: ProcessHandle,
+    broker: Broker,
+    policy: ShutdownPolicy,
+}
+
+/// Keeps a client or supervisor in the registry until dropped along with it.
+pub(crate) struct Registration(u64);
+
+impl Registration {
+    pub(crate) fn register(
+        controller: ControllerClient,
+        process: ProcessHandle,
+        broker: Broker,
+        policy: ShutdownPolicy,
+    ) -> Self {
+        let mut registry = lock_ignore_poison(&REGISTRY);
+
+        let id = registry.next_id();
+        registry.clients.insert(
+            id,
+            Managed {
+                controller,
+                process,
+                broker,
+                policy,
+            },
+        );
+
+        Self(id)
+    }
+
+    pub(crate) fn supervisor() -> Self {
+        let mut registry = lock_ignore_poison(&REGISTRY);
+
+        let id = registry.next_id();
+        registry.supervisors.insert(id);
+
+        Self(id)
+    }
+
+    /// Whether [`cleanup_all`] has run since registering.
+    pub(crate) fn is_cleaned_up(&self) -> bool {
+        let registry = lock_ignore_poison(&REGISTRY);
+        !registry.clients.contains_key(&self.0) && !registry.supervisors.contains(&self.0)
+    }
+}
+
+impl Registry {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut registry = lock_ignore_poison(&REGISTRY);
+        registry.clients.remove(&self.0);
+        registry.supervisors.remove(&self.0);
+    }
+}
+
+/// Stop every managed plugin concurrently, each following its
+/// [`ShutdownPolicy`], like go-plugin's `CleanupClients`.
+///
+/// Supervisors stop restarting their plugin first, so the plugins stay stopped.
+///
+/// Plugins still running once `timeout` elapses are sent SIGKILL without waiting for them, and
+/// the call fails. Meant to be called before the host exits, since [`std::process::exit`] or an
+/// aborting panic skips the drops that otherwise kill the plugins.
+pub async fn cleanup_all(timeout: Duration) -> Result<(), PluginxError> {
+    let managed = {
+        let mut registry = lock_ignore_poison(&REGISTRY);
+        registry.supervisors.clear();
+        mem::take(&mut registry.clients)
+    };
+    let processes: Vec<_> = managed.values().map(|m| m.process.clone()).collect();
+
+    let stops = managed.into_values().map(|mut m| async move {
+        m.broker.close();
+        stop(&mut m.controller, &m.process, &m.policy).await
+    });
+
+    if let Ok(outcomes) = timeout_after(timeout, join_all(stops)).await {
+        return outcomes
+            .into_iter()
+            .try_for_each(|outcome| outcome.map(drop));
+    }
+
+    let running = processes
+        .iter()
+        .filter(|process| process.try_status().is_none())
+        .inspect(|process| process.kill())
+        .count();
+
+    Err(io::Error::new(
+        ErrorKind::TimedOut,
+        format!("{running} plugins didn't exit within {timeout:?}, killed them"),
+    ))?
+}
@@ -0,0 +1,501 @@
+use std::{
+    cell::Cell,
+    ffi::CStr,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, ErrorKind},
+    iter,
+    mem::{offset_of, size_of},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use tokio::process::{Child, Command};
+
+use super::{
+    config::{FsAccess, FsRule, Namespaces, SandboxConfig, SeccompFilter},
+    secure,
+};
+use crate::PluginxError;
+
+// Landlock uapi, missing from libc
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Rights that apply to files, the others only make sense beneath directories.
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: RawFd,
+}
+
+/// `AUDIT_ARCH_*` of the target, the syscall numbers of the filter are only valid for it.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00f3);
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Set on the numbers of x32 syscalls, which x86_64 processes can make as well.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+thread_local! {
+    /// Where the hook of the child spawned from this thread records its [`Step`].
+    static STEP: Cell<*const AtomicU8> = const { Cell::new(ptr::null()) };
+}
+
+/// The part of the hook being run, only the error number of a failing hook reaches the host.
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+enum Step {
+    /// not started, or done
+    Idle,
+    Unshare,
+    IdMaps,
+    MountPropagation,
+    NoNewPrivs,
+    Landlock,
+    Seccomp,
+}
+
+/// Install the hook applying `config` to the plugin spawned by `cmd`.
+///
+/// Everything is prepared here, the hook only makes syscalls: namespaces are entered first,
+/// then no new privileges, Landlock and seccomp restrict the process. Hooks added later run
+/// under the seccomp filter.
+pub(crate) fn apply(cmd: &mut Command, config: &SandboxConfig) -> io::Result<()> {
+    let namespaces = NamespaceSetup::new(config.namespaces);
+    let ruleset = match &config.landlock {
+        Some(rules) => {
+            let program = secure::resolve(Path::new(cmd.as_std().get_program()))?;
+            Some(ruleset(rules, &program)?)
+        }
+        None => None,
+    };
+    let filter = config.seccomp.as_ref().map(filter).transpose()?;
+    let no_new_privs = config.no_new_privs || ruleset.is_some() || filter.is_some();
+
+    // SAFETY: only async-signal-safe functions are called between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            namespaces.enter()?;
+
+            Step::NoNewPrivs.record();
+            if no_new_privs && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Step::Landlock.record();
+            if let Some(ruleset) = &ruleset
+                && libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Step::Seccomp.record();
+            if let Some(filter) = &filter {
+                let program = libc::sock_fprog {
+                    len: filter.len() as u16,
+                    filter: filter.as_ptr().cast_mut(),
+                };
+                let mode = libc::SECCOMP_SET_MODE_FILTER;
+                if libc::syscall(libc::SYS_seccomp, mode, 0, &program) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            // failures of the following hooks, or of exec, aren't the sandbox's
+            Step::Idle.record();
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+/// Spawn `cmd` sandboxed by [`apply`], failing with [`PluginxError::Sandbox`] naming the step if
+/// the sandbox couldn't be set up in the plugin process.
+pub(crate) fn spawn(
+    cmd: &mut Command,
+    spawn: fn(&mut Command) -> io::Result<Child>,
+) -> Result<Child, PluginxError> {
+    let step = SharedStep::new()?;
+
+    // the fork happens on this thread, and the child writes to the shared page
+    STEP.set(step.0.as_ptr());
+    let child = spawn(cmd);
+    STEP.set(ptr::null());
+
+    child.map_err(|e| match step.load() {
+        Step::Idle => e.into(),
+        failed => PluginxError::Sandbox(io::Error::new(e.kind(), format!("{failed}: {e}"))),
+    })
+}
+
+impl Step {
+    const ALL: [Self; 7] = [
+        Self::Idle,
+        Self::Unshare,
+        Self::IdMaps,
+        Self::MountPropagation,
+        Self::NoNewPrivs,
+        Self::Landlock,
+        Self::Seccomp,
+    ];
+
+    /// Called in the plugin process.
+    fn record(self) {
+        // SAFETY: null or the page of a `SharedStep` alive until the spawn returned
+        if let Some(step) = unsafe { STEP.get().as_ref() } {
+            step.store(self as u8, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Idle => "idle",
+            Self::Unshare => "entering namespaces",
+            Self::IdMaps => "writing uid and gid maps",
+            Self::MountPropagation => "making mounts private",
+            Self::NoNewPrivs => "setting no_new_privs",
+            Self::Landlock => "restricting with landlock",
+            Self::Seccomp => "installing the seccomp filter",
+        })
+    }
+}
+
+/// A page shared with the plugin process, forked children write to the same memory.
+struct SharedStep(NonNull<AtomicU8>);
+
+impl SharedStep {
+    fn new() -> io::Result<Self> {
+        // SAFETY: a new anonymous mapping, zeroed so it reads as `Step::Idle`
+        let page = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size_of::<AtomicU8>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if page == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(
+            NonNull::new(page.cast()).expect("mmap never maps at null"),
+        ))
+    }
+
+    fn load(&self) -> Step {
+        // SAFETY: mapped until dropped
+        let step = unsafe { self.0.as_ref() }.load(Ordering::Relaxed);
+        Step::ALL.get(step as usize).copied().unwrap_or(Step::Idle)
+    }
+}
+
+impl Drop for SharedStep {
+    fn drop(&mut self) {
+        // SAFETY: mapped by `new`, nothing refers to it anymore
+        unsafe { libc::munmap(self.0.as_ptr().cast(), size_of::<AtomicU8>()) };
+    }
+}
+
+/// What entering the namespaces takes, prepared before forking.
+struct NamespaceSetup {
+    flags: libc::c_int,
+    /// `uid_map` and `gid_map` of the user namespace
+    maps: Option<(Vec<u8>, Vec<u8>)>,
+    mount: bool,
+}
+
+impl NamespaceSetup {
+    fn new(namespaces: Namespaces) -> Self {
+        let flags = [
+            (namespaces.user, libc::CLONE_NEWUSER),
+            (namespaces.network, libc::CLONE_NEWNET),
+            (namespaces.mount, libc::CLONE_NEWNS),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .fold(0, |flags, (_, flag)| flags | flag);
+
+        // SAFETY: both always succeed
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let maps = namespaces.user.then(|| {
+            (
+                format!("{uid} {uid} 1").into_bytes(),
+                format!("{gid} {gid} 1").into_bytes(),
+            )
+        });
+
+        Self {
+            flags,
+            maps,
+            mount: namespaces.mount,
+        }
+    }
+
+    /// Called in the plugin process.
+    fn enter(&self) -> io::Result<()> {
+        if self.flags == 0 {
+            return Ok(());
+        }
+
+        Step::Unshare.record();
+        // SAFETY: async-signal-safe
+        if unsafe { libc::unshare(self.flags) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if let Some((uid_map, gid_map)) = &self.maps {
+            Step::IdMaps.record();
+            // unprivileged processes may only write gid_map once setgroups is denied
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", uid_map)?;
+            write_file(c"/proc/self/gid_map", gid_map)?;
+        }
+
+        // or mounts of the plugin would propagate back to the host
+        Step::MountPropagation.record();
+        // SAFETY: async-signal-safe, every pointer is null or a valid string
+        if self.mount
+            && unsafe {
+                libc::mount(
+                    ptr::null(),
+                    c"/".as_ptr(),
+                    ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    ptr::null(),
+                )
+            } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `data` to `path` without allocating, for the plugin process.
+fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    // SAFETY: open, write and close are async-signal-safe, the descriptor is a new one
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+
+        if libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Create a Landlock ruleset granting `rules`, and the execution of `program`.
+fn ruleset(rules: &[FsRule], program: &Path) -> io::Result<OwnedFd> {
+    // SAFETY: without attributes the ABI version is returned
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+
+    // every right the kernel knows of, so the rules are all the access left
+    let handled = match abi {
+        ..=0 => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "landlock isn't enabled in the kernel",
+            ));
+        }
+        1 => (1 << 13) - 1,
+        2 => (1 << 14) - 1,
+        3 | 4 => (1 << 15) - 1,
+        _ => (1 << 16) - 1,
+    };
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` outlives the call, its size is passed along
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: a new descriptor, owned by nothing else
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+    let program = FsRule::new(program, FsAccess::ReadExecute);
+    for rule in rules.iter().chain(iter::once(&program)) {
+        add_rule(&ruleset, rule, handled)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", rule.path.display())))?;
+    }
+
+    Ok(ruleset)
+}
+
+fn add_rule(ruleset: &OwnedFd, rule: &FsRule, handled: u64) -> io::Result<()> {
+    let file = match File::options()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(&rule.path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut allowed = handled
+        & match rule.access {
+            FsAccess::Read => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            FsAccess::ReadExecute => ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            FsAccess::ReadWrite => {
+                !(ACCESS_FS_EXECUTE | ACCESS_FS_MAKE_CHAR | ACCESS_FS_MAKE_BLOCK)
+            }
+        };
+    if !file.metadata()?.is_dir() {
+        allowed &= ACCESS_FS_FILE;
+    }
+
+    let attr = PathBeneathAttr {
+        allowed_access: allowed,
+        parent_fd: file.as_raw_fd(),
+    };
+    // SAFETY: `attr` outlives the call, and `file` is open until after it
+    let added = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr,
+            0,
+        )
+    };
+    if added == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Compile `seccomp` into a BPF program making the denied syscalls fail with `EPERM`.
+fn filter(seccomp: &SeccompFilter) -> io::Result<Vec<libc::sock_filter>> {
+    let Some(arch) = AUDIT_ARCH else {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "seccomp filters aren't supported on this architecture",
+        ));
+    };
+
+    let deny = statement(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    );
+    let mut program = vec![
+        statement(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            offset_of!(libc::seccomp_data, arch) as u32,
+        ),
+        // numbers of another architecture would be other syscalls
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            offset_of!(libc::seccomp_data, nr) as u32,
+        ),
+    ];
+    if cfg!(target_arch = "x86_64") {
+        // the whole x32 ABI, its numbers carry the x32 bit so the list below wouldn't match them
+        program.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            deny,
+        ]);
+    }
+    for &nr in &seccomp.denied {
+        program.extend([
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1),
+            deny,
+        ]);
+    }
+    program.push(statement(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ALLOW,
+    ));
+
+    if program.len() > libc::BPF_MAXINSNS as usize {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "too many syscalls denied by the seccomp filter",
+        ));
+    }
+
+    Ok(program)
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
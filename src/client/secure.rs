@@ -0,0 +1,443 @@
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::task::spawn_blocking;
+
+use super::config::{Checksum, SecureConfig};
+use crate::PluginxError;
+
+/// Open the executable of `program`, resolved like the shell does, and run the checks of
+/// `config` on it. The returned file is the one verified, whatever happens to the path
+/// afterwards.
+pub(crate) async fn verify(program: &OsStr, config: SecureConfig) -> Result<File, PluginxError> {
+    let path = resolve(Path::new(program))?;
+
+    spawn_blocking(move || verify_file(&path, &config)).await?
+}
+
+fn verify_file(path: &Path, config: &SecureConfig) -> Result<File, PluginxError> {
+    let mut file = File::open(path)?;
+
+    // a single pass over the executable, whatever the checks
+    let mut digests = Digests {
+        sha256: matches!(config.checksum, Some(Checksum::Sha256(_))).then(Sha256::new),
+        sha512: (matches!(config.checksum, Some(Checksum::Sha512(_)))
+            || !config.trusted_keys.is_empty())
+        .then(Sha512::new),
+    };
+    io::copy(&mut file, &mut digests)?;
+
+    if let Some(checksum) = &config.checksum {
+        verify_checksum(path, &digests, checksum)?;
+    }
+    if !config.trusted_keys.is_empty() {
+        let sha512 = digests.sha512.expect("hashed whenever keys are trusted");
+        verify_signature(path, sha512, &config.trusted_keys)?;
+    }
+
+    Ok(file)
+}
+
+/// The digests the checks need, fed in chunks by [`io::copy`] rather than from the whole
+/// executable in memory.
+struct Digests {
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+}
+
+impl Write for Digests {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(buf);
+        }
+        if let Some(sha512) = &mut self.sha512 {
+            sha512.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn verify_checksum(
+    path: &Path,
+    digests: &Digests,
+    checksum: &Checksum,
+) -> Result<(), PluginxError> {
+    // both are set whenever the matching checksum is
+    let actual = match checksum {
+        Checksum::Sha256(_) => digests.sha256.clone().map(|d| d.finalize().to_vec()),
+        Checksum::Sha512(_) => digests.sha512.clone().map(|d| d.finalize().to_vec()),
+    }
+    .unwrap_or_default();
+
+    if actual != checksum.as_bytes() {
+        return Err(PluginxError::ChecksumMismatch {
+            path: path.to_owned(),
+            expected: hex(checksum.as_bytes()),
+            actual: hex(&actual),
+        });
+    }
+
+    Ok(())
+}
+
+/// Check the Ed25519ph signature, over the SHA-512 digest of the executable.
+fn verify_signature(
+    path: &Path,
+    sha512: Sha512,
+    trusted_keys: &[[u8; PUBLIC_KEY_LENGTH]],
+) -> Result<(), PluginxError> {
+    let mut sig_path = OsString::from(path);
+    sig_path.push(".sig");
+    let path = PathBuf::from(sig_path);
+
+    let sig = match fs::read(&path) {
+        Ok(sig) => sig,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(PluginxError::MissingSignature { path });
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if sig.len() != PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH {
+        return Err(PluginxError::BadSignature { path });
+    }
+    // the public key of the signer, then the signature
+    let (key, signature) = sig.split_at(PUBLIC_KEY_LENGTH);
+
+    if !trusted_keys.iter().any(|trusted| trusted == key) {
+        return Err(PluginxError::UntrustedKey {
+            path,
+            key: hex(key),
+        });
+    }
+
+    match (
+        VerifyingKey::try_from(key),
+        Signature::from_slice(signature),
+    ) {
+        (Ok(key), Ok(signature))
+            if key
+                .verify_prehashed_strict(sha512, None, &signature)
+                .is_ok() =>
+        {
+            Ok(())
+        }
+        _ => Err(PluginxError::BadSignature { path }),
+    }
+}
+
+/// the executable run for `program`, looked up in `PATH` unless it is a path
+pub(crate) fn resolve(program: &Path) -> io::Result<PathBuf> {
+    if program.components().count() > 1 {
+        return Ok(program.to_owned());
+    }
+
+    env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "plugin executable not found"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use exec::{exec_verified, spawn};
+
+#[cfg(target_os = "linux")]
+mod exec {
+    use std::{
+        cell::Cell,
+        collections::BTreeMap,
+        env,
+        ffi::{c_char, CString, OsStr},
+        fs::File,
+        io, iter,
+        os::{
+            fd::{AsRawFd, OwnedFd},
+            unix::ffi::OsStrExt,
+        },
+        ptr,
+    };
+
+    use tokio::process::{Child, Command};
+
+    thread_local! {
+        /// `envp` of the child spawned from this thread, read by the hook after forking.
+        static ENVP: Cell<*const *const c_char> = const { Cell::new(ptr::null()) };
+    }
+
+    unsafe extern "C" {
+        static environ: *const *const c_char;
+    }
+
+    /// A null terminated array of C strings, built before forking since the child must not
+    /// allocate.
+    struct CStrings {
+        _strings: Vec<CString>,
+        pointers: Vec<*const c_char>,
+    }
+
+    // SAFETY: the pointers refer to the owned strings, which are never mutated
+    unsafe impl Send for CStrings {}
+    unsafe impl Sync for CStrings {}
+
+    impl CStrings {
+        fn new<T: Into<Vec<u8>>>(strings: impl Iterator<Item = T>) -> io::Result<Self> {
+            let strings = strings.map(CString::new).collect::<Result<Vec<_>, _>>()?;
+            let pointers = strings
+                .iter()
+                .map(|s| s.as_ptr())
+                .chain(iter::once(ptr::null()))
+                .collect();
+
+            Ok(Self {
+                _strings: strings,
+                pointers,
+            })
+        }
+
+        fn as_ptr(&self) -> *const *const c_char {
+            self.pointers.as_ptr()
+        }
+    }
+
+    /// Make `cmd` execute `file` itself instead of looking its program up again, so the
+    /// executable can't be swapped after the check. It must then be started with [`spawn`].
+    ///
+    /// `fexecve` is called from the last hook before exec, once std set the stdio of the child
+    /// up. Scripts aren't supported, their interpreter can't open the descriptor closed on exec.
+    pub(crate) fn exec_verified(cmd: &mut Command, file: File) -> io::Result<()> {
+        let std_cmd = cmd.as_std();
+        let argv = CStrings::new(
+            iter::once(std_cmd.get_program())
+                .chain(std_cmd.get_args())
+                .map(OsStr::as_bytes),
+        )?;
+        let fd = OwnedFd::from(file);
+
+        // SAFETY: fexecve is async-signal-safe and only returns on failure, the thread local
+        // holds a plain pointer and needs no initialization
+        unsafe {
+            cmd.pre_exec(move || {
+                let envp = match ENVP.get() {
+                    envp if envp.is_null() => environ,
+                    envp => envp,
+                };
+                libc::fexecve(fd.as_raw_fd(), argv.as_ptr(), envp);
+                Err(io::Error::last_os_error())
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Spawn `cmd`, handing the environment std would give it to the hook of
+    /// [`exec_verified`]: std only sets it up after the hooks ran. Clearing the environment
+    /// with `env_clear` isn't visible on the command, and so not supported.
+    pub(crate) fn spawn(cmd: &mut Command) -> io::Result<Child> {
+        let mut vars: BTreeMap<_, _> = env::vars_os().collect();
+        for (key, value) in cmd.as_std().get_envs() {
+            match value {
+                Some(value) => vars.insert(key.to_owned(), value.to_owned()),
+                None => vars.remove(key),
+            };
+        }
+        let envp = CStrings::new(
+            vars.iter()
+                .map(|(key, value)| [key.as_bytes(), b"=", value.as_bytes()].concat()),
+        )?;
+
+        // the fork happens on this thread, and the child gets a copy of `envp`
+        ENVP.set(envp.as_ptr());
+        let child = cmd.spawn();
+        ENVP.set(ptr::null());
+
+        child
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const EXECUTABLE: &[u8] = b"\x7fELF not really a plugin";
+
+    /// An executable and its `.sig` in the temp dir, removed once dropped.
+    struct Plugin(PathBuf);
+
+    impl Plugin {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("pluginx-secure-{}-{name}", process::id()));
+            fs::write(&path, EXECUTABLE).unwrap();
+            Self(path)
+        }
+
+        fn sig_path(&self) -> PathBuf {
+            let mut path = self.0.clone().into_os_string();
+            path.push(".sig");
+            path.into()
+        }
+
+        /// Sign with Ed25519ph, the public key first.
+        fn sign(&self, key: &SigningKey) {
+            let signature = key
+                .sign_prehashed(Sha512::new_with_prefix(EXECUTABLE), None)
+                .unwrap();
+            self.write_sig(&[key.verifying_key().as_bytes(), &signature.to_bytes()[..]].concat());
+        }
+
+        fn write_sig(&self, sig: &[u8]) {
+            fs::write(self.sig_path(), sig).unwrap();
+        }
+
+        fn verify(&self, config: SecureConfig) -> Result<File, PluginxError> {
+            verify_file(&self.0, &config)
+        }
+    }
+
+    impl Drop for Plugin {
+        fn drop(&mut self) {
+            _ = fs::remove_file(&self.0);
+            _ = fs::remove_file(self.sig_path());
+        }
+    }
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn trusting(keys: &[&SigningKey]) -> SecureConfig {
+        SecureConfig {
+            checksum: None,
+            trusted_keys: keys
+                .iter()
+                .map(|key| key.verifying_key().to_bytes())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn checksum_match() {
+        let plugin = Plugin::new("checksum-match");
+
+        for checksum in [
+            Checksum::Sha256(Sha256::digest(EXECUTABLE).into()),
+            Checksum::Sha512(Sha512::digest(EXECUTABLE).into()),
+        ] {
+            let config = SecureConfig {
+                checksum: Some(checksum),
+                trusted_keys: Vec::new(),
+            };
+            plugin.verify(config).unwrap();
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let plugin = Plugin::new("checksum-mismatch");
+        let config = SecureConfig {
+            checksum: Some(Checksum::Sha256([0; 32])),
+            trusted_keys: Vec::new(),
+        };
+
+        let Err(PluginxError::ChecksumMismatch {
+            expected, actual, ..
+        }) = plugin.verify(config)
+        else {
+            panic!("checksum accepted");
+        };
+        assert_eq!(expected, "00".repeat(32));
+        assert_eq!(actual, hex(&Sha256::digest(EXECUTABLE)));
+    }
+
+    #[test]
+    fn valid_signature() {
+        let plugin = Plugin::new("valid-signature");
+        let (key, other) = (signing_key(1), signing_key(2));
+        plugin.sign(&key);
+
+        plugin.verify(trusting(&[&other, &key])).unwrap();
+    }
+
+    #[test]
+    fn missing_signature() {
+        let plugin = Plugin::new("missing-signature");
+
+        let result = plugin.verify(trusting(&[&signing_key(1)]));
+        assert!(
+            matches!(result, Err(PluginxError::MissingSignature { path }) if path == plugin.sig_path())
+        );
+    }
+
+    #[test]
+    fn wrong_length_signature() {
+        let plugin = Plugin::new("wrong-length-signature");
+        let key = signing_key(1);
+        plugin.write_sig(
+            &[
+                &key.verifying_key().to_bytes()[..],
+                &[0; SIGNATURE_LENGTH - 1],
+            ]
+            .concat(),
+        );
+
+        let result = plugin.verify(trusting(&[&key]));
+        assert!(matches!(result, Err(PluginxError::BadSignature { .. })));
+    }
+
+    #[test]
+    fn untrusted_key() {
+        let plugin = Plugin::new("untrusted-key");
+        let key = signing_key(1);
+        plugin.sign(&key);
+
+        let Err(PluginxError::UntrustedKey { key: untrusted, .. }) =
+            plugin.verify(trusting(&[&signing_key(2)]))
+        else {
+            panic!("untrusted key accepted");
+        };
+        assert_eq!(untrusted, hex(key.verifying_key().as_bytes()));
+    }
+
+    #[test]
+    fn tampered_executable() {
+        let plugin = Plugin::new("tampered-executable");
+        let key = signing_key(1);
+        plugin.sign(&key);
+        fs::write(&plugin.0, [EXECUTABLE, b"!"].concat()).unwrap();
+
+        let result = plugin.verify(trusting(&[&key]));
+        assert!(matches!(result, Err(PluginxError::BadSignature { .. })));
+    }
+
+    #[test]
+    fn plain_signature() {
+        // signed over the executable itself rather than its digest
+        let plugin = Plugin::new("plain-signature");
+        let key = signing_key(1);
+        let signature = key.sign(EXECUTABLE);
+        plugin.write_sig(&[key.verifying_key().as_bytes(), &signature.to_bytes()[..]].concat());
+
+        let result = plugin.verify(trusting(&[&key]));
+        assert!(matches!(result, Err(PluginxError::BadSignature { .. })));
+    }
+}
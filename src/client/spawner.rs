@@ -0,0 +1,81 @@
+use std::{
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    thread,
+};
+
+use tokio::{
+    process::{Child, Command},
+    runtime::Handle,
+    sync::oneshot,
+};
+
+use crate::PluginxError;
+
+/// The thread spawning plugins with `PR_SET_PDEATHSIG`, which the kernel ties to the thread
+/// forking the plugin rather than to the host. Runtime threads come and go, e.g. blocking ones
+/// or those of a short-lived `block_on`, this one lives as long as the host.
+static SPAWNER: OnceLock<Sender<Request>> = OnceLock::new();
+
+struct Request {
+    cmd: Command,
+    runtime: Handle,
+    spawn: Spawn,
+    reply: oneshot::Sender<(Command, Result<Child, PluginxError>)>,
+}
+
+type Spawn = Box<dyn FnOnce(&mut Command) -> Result<Child, PluginxError> + Send>;
+
+/// Run `spawn` on the spawner thread, `cmd` is handed back once done.
+pub(crate) async fn spawn(
+    cmd: &mut Command,
+    spawn: impl FnOnce(&mut Command) -> Result<Child, PluginxError> + Send + 'static,
+) -> Result<Child, PluginxError> {
+    let (reply, replied) = oneshot::channel();
+    let request = Request {
+        cmd: mem::replace(cmd, Command::new("")),
+        runtime: Handle::current(),
+        spawn: Box::new(spawn),
+        reply,
+    };
+
+    if let Err(mpsc::SendError(request)) = spawner()?.send(request) {
+        *cmd = request.cmd;
+        Err(io::Error::other("the spawner thread is gone"))?;
+    }
+
+    // every request is answered, even if spawning panics
+    let (taken, child) = replied
+        .await
+        .map_err(|_| io::Error::other("the spawner thread is gone"))?;
+    *cmd = taken;
+    child
+}
+
+fn spawner() -> io::Result<&'static Sender<Request>> {
+    if let Some(spawner) = SPAWNER.get() {
+        return Ok(spawner);
+    }
+
+    let (tx, rx) = mpsc::channel::<Request>();
+    thread::Builder::new()
+        .name("pluginx-spawner".into())
+        .spawn(move || {
+            for mut request in rx {
+                let _runtime = request.runtime.enter();
+                let spawn = request.spawn;
+                let child = panic::catch_unwind(AssertUnwindSafe(|| spawn(&mut request.cmd)))
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::other("spawning the plugin panicked").into())
+                    });
+                _ = request.reply.send((request.cmd, child));
+            }
+        })?;
+
+    // racing callers spawn a thread each, the losers exit as their sender is dropped
+    Ok(SPAWNER.get_or_init(|| tx))
+}
@@ -0,0 +1,158 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+use super::{config::ClientConfig, registry::Registration, Client, ClientBuilder, ShutdownOutcome};
+use crate::{common::utils::lock_ignore_poison, plugin::PluginClient, PluginxError};
+
+/// A [`Client`] that restarts the plugin whenever it exits, following
+/// [`ClientConfig::restart_policy`].
+///
+/// A [`managed`](ClientConfig::managed) one stops restarting once
+/// [`cleanup_all`](super::cleanup_all) runs.
+///
+/// Every restart redoes the handshake and registers the plugins of
+/// [`ClientConfig::versioned_plugins`] again, so those are the only plugins it can dispense.
+pub struct SupervisedClient {
+    shared: Arc<Shared>,
+    supervisor: JoinHandle<()>,
+}
+
+struct Shared {
+    /// the live instance, [`None`] while restarting or after giving up
+    client: Mutex<Option<Client>>,
+    restarts: AtomicU32,
+    registration: Option<Registration>,
+}
+
+impl SupervisedClient {
+    /// Start the plugin, failing like [`ClientBuilder::new`] if the first instance doesn't come up.
+    pub async fn new(mut config: ClientConfig) -> Result<Self, PluginxError> {
+        ClientBuilder::configure(&mut config).await?;
+        let client = ClientBuilder::spawn(&mut config).await?.build();
+
+        let shared = Arc::new(Shared {
+            client: Mutex::new(Some(client)),
+            restarts: AtomicU32::new(0),
+            registration: config.managed.then(Registration::supervisor),
+        });
+        let supervisor = tokio::spawn(Self::supervise(shared.clone(), config));
+
+        Ok(Self { shared, supervisor })
+    }
+
+    /// Dispense from the live instance, [`None`] while the plugin is restarting, after giving up
+    /// or if `P` isn't registered.
+    pub fn dispense<P: PluginClient + 'static>(&self) -> Option<P::Client> {
+        lock_ignore_poison(&self.shared.client)
+            .as_ref()?
+            .dispense::<P>()
+    }
+
+    /// Access the live instance, e.g. for its broker or stdio.
+    pub fn with_client<R>(&self, f: impl FnOnce(&mut Client) -> R) -> Option<R> {
+        lock_ignore_poison(&self.shared.client).as_mut().map(f)
+    }
+
+    pub fn is_running(&self) -> bool {
+        lock_ignore_poison(&self.shared.client).is_some()
+    }
+
+    /// restarts done so far
+    pub fn restarts(&self) -> u32 {
+        self.shared.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Stop supervising and shut the live instance down like [`Client::shutdown`].
+    pub async fn shutdown(mut self) -> Result<Option<ShutdownOutcome>, PluginxError> {
+        // a restart in progress is dropped along with its plugin
+        self.supervisor.abort();
+        _ = (&mut self.supervisor).await;
+
+        let client = lock_ignore_poison(&self.shared.client).take();
+        match client {
+            Some(client) => client.shutdown().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn supervise(shared: Arc<Shared>, mut config: ClientConfig) {
+        let policy = config.restart_policy.clone();
+        let mut backoff = policy.initial_backoff;
+        let mut in_row = 0;
+
+        loop {
+            let started = Instant::now();
+            let exited = match lock_ignore_poison(&shared.client).as_ref() {
+                Some(client) => client.exited(),
+                None => return,
+            };
+            exited.await;
+
+            lock_ignore_poison(&shared.client).take();
+            if shared.is_cleaned_up() {
+                return;
+            }
+
+            if started.elapsed() >= policy.reset_after {
+                backoff = policy.initial_backoff;
+                in_row = 0;
+            }
+
+            let client = loop {
+                if policy.max_restarts.is_some_and(|max| in_row >= max) {
+                    return;
+                }
+
+                in_row += 1;
+                shared.restarts.fetch_add(1, Ordering::Relaxed);
+
+                sleep(backoff).await;
+                backoff = next_backoff(backoff, policy.max_backoff);
+                if shared.is_cleaned_up() {
+                    return;
+                }
+
+                // failing to start counts as another restart
+                if let Ok(builder) = ClientBuilder::spawn(&mut config).await {
+                    break builder.build();
+                }
+            };
+
+            // the new instance is registered by now, either cleanup_all already has it or it's
+            // killed when dropped here
+            if shared.is_cleaned_up() {
+                return;
+            }
+
+            *lock_ignore_poison(&shared.client) = Some(client);
+        }
+    }
+}
+
+impl Shared {
+    fn is_cleaned_up(&self) -> bool {
+        self.registration
+            .as_ref()
+            .is_some_and(Registration::is_cleaned_up)
+    }
+}
+
+impl Drop for SupervisedClient {
+    fn drop(&mut self) {
+        self.supervisor.abort();
+    }
+}
+
+fn next_backoff(backoff: Duration, max: Duration) -> Duration {
+    backoff.saturating_mul(2).min(max)
+}
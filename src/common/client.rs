@@ -1,14 +1,21 @@
-use std::any::{Any, TypeId};
+use std::{
+    any::{Any, TypeId},
+    future::Future,
+    io::Result as IoResult,
+    sync::Arc,
+};
 
 use foldhash::{HashMap, HashMapExt};
-use futures_util::TryFutureExt;
 use hyper_util::rt::TokioIo;
-use tokio::net::UnixStream;
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::TlsConnector;
 use tonic::transport::{Channel, Uri};
+use yamux::Mode;
 
 use crate::{
-    common::utils::service_fn,
-    handshake::{HandshakeError, Network},
+    common::{io::BoxedIo, mux::Mux, tls::SERVER_NAME, utils::service_fn},
+    handshake::Network,
     PluginxError,
 };
 
@@ -19,27 +26,23 @@ pub(crate) struct Client {
 }
 
 impl Client {
-    pub(crate) async fn new(network: Network) -> Result<Self, PluginxError> {
-        let channel = match &network {
-            Network::Tcp(addr) => {
-                let uri = Uri::builder()
-                    .scheme("http")
-                    .authority(addr.to_string())
-                    .build()
-                    .map_err(|_| PluginxError::Handshake {
-                        error: HandshakeError::InvalidNetwork,
-                        message: addr.to_string(),
-                    })?;
-                Channel::builder(uri).connect().await?
-            }
-            Network::Unix(path) => {
-                let path = path.to_owned();
-                Channel::from_static("http://pluginx")
-                    .connect_with_connector(service_fn(move |_: Uri| {
-                        UnixStream::connect(path.clone()).map_ok(TokioIo::new)
-                    }))
-                    .await?
+    /// With `mux`, the plugin is dialed only once and the gRPC connection is multiplexed over it.
+    pub(crate) async fn new(
+        network: Network,
+        mux: Option<Mux>,
+        tls: Option<Arc<ClientConfig>>,
+    ) -> Result<Self, PluginxError> {
+        let channel = match mux {
+            Some(mux) => {
+                // the plugin only opens streams it was knocked for, nothing is left to accept
+                mux.start(dial(&network).await?, Mode::Client);
+                let dialer = move || {
+                    let mux = mux.clone();
+                    async move { mux.open().await }
+                };
+                connect_with(dialer, tls).await?
             }
+            None => connect(network.clone(), tls).await?,
         };
 
         Ok(Self {
@@ -72,6 +75,65 @@ impl Client {
     }
 }
 
+/// Connect a gRPC channel to `network`, wrapping every connection with TLS if `tls` is given.
+pub(crate) async fn connect(
+    network: Network,
+    tls: Option<Arc<ClientConfig>>,
+) -> Result<Channel, PluginxError> {
+    connect_with(
+        move || {
+            let network = network.clone();
+            async move { dial(&network).await }
+        },
+        tls,
+    )
+    .await
+}
+
+/// Connect a gRPC channel over the connections made by `dialer`, wrapping them with TLS if `tls`
+/// is given.
+pub(crate) async fn connect_with<D, F>(
+    dialer: D,
+    tls: Option<Arc<ClientConfig>>,
+) -> Result<Channel, PluginxError>
+where
+    D: Fn() -> F + Clone + Send + 'static,
+    F: Future<Output = IoResult<BoxedIo>> + Send + 'static,
+{
+    let channel = Channel::from_static("http://pluginx")
+        .connect_with_connector(service_fn(move |_: Uri| {
+            let (io, tls) = (dialer(), tls.clone());
+            async move { with_tls(io.await?, tls).await.map(TokioIo::new) }
+        }))
+        .await?;
+
+    Ok(channel)
+}
+
+async fn dial(network: &Network) -> IoResult<BoxedIo> {
+    let io = match network {
+        Network::Tcp(addr) => {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            BoxedIo::new(stream)
+        }
+        Network::Unix(path) => BoxedIo::new(UnixStream::connect(path).await?),
+    };
+
+    Ok(io)
+}
+
+async fn with_tls(io: BoxedIo, tls: Option<Arc<ClientConfig>>) -> IoResult<BoxedIo> {
+    match tls {
+        Some(config) => {
+            let name = ServerName::try_from(SERVER_NAME).expect("valid DNS name");
+            let stream = TlsConnector::from(config).connect(name, io).await?;
+            Ok(BoxedIo::new(stream))
+        }
+        None => Ok(io),
+    }
+}
+
 impl Drop for Client {
     fn drop(&mut self) {
         // TODO: shutdown in sync context
@@ -1,3 +1,6 @@
 pub mod client;
+pub mod io;
+pub mod mux;
 pub mod server;
+pub mod tls;
 pub mod utils;
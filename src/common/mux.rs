@@ -0,0 +1,184 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    sync::{Arc, Mutex, OnceLock},
+    task::Poll,
+    time::Duration,
+};
+
+use foldhash::HashMap;
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendError, UnboundedReceiver, UnboundedSender},
+        oneshot, Notify,
+    },
+    time::{timeout_at, Instant},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use yamux::{Config, Connection, Mode};
+
+use super::{io::BoxedIo, utils::lock_ignore_poison};
+
+type OpenRequest = oneshot::Sender<IoResult<BoxedIo>>;
+
+/// go-plugin's gRPC broker multiplexing: a yamux session over the only connection between host
+/// and plugin, carrying the main gRPC connection as well as the broker connections.
+///
+/// The side serving a broker service listens on its id. The dialing side knocks through the
+/// broker stream, [`Mux::accept_knock`] routes the next inbound stream to that listener, and the
+/// dialer opens the stream once the knock is acknowledged. Inbound streams nobody knocked for
+/// belong to the main gRPC server.
+#[derive(Clone, Default)]
+pub(crate) struct Mux(Arc<MuxInner>);
+
+#[derive(Default)]
+struct MuxInner {
+    /// requests to open streams, set once the session is started
+    open: OnceLock<UnboundedSender<OpenRequest>>,
+    routes: Mutex<Routes>,
+    /// notified when a listener is added
+    listening: Notify,
+}
+
+#[derive(Default)]
+struct Routes {
+    listeners: HashMap<u32, UnboundedSender<BoxedIo>>,
+    /// listeners of the knocks acknowledged so far, in order
+    knocked: VecDeque<UnboundedSender<BoxedIo>>,
+    fallback: Option<UnboundedSender<BoxedIo>>,
+}
+
+impl Mux {
+    /// Run the session over `io`. The returned stream yields the inbound streams nobody knocked
+    /// for, and ends with the session.
+    pub(crate) fn start(&self, io: BoxedIo, mode: Mode) -> UnboundedReceiverStream<BoxedIo> {
+        let (open_tx, open_rx) = mpsc::unbounded_channel();
+        let (fallback_tx, fallback_rx) = mpsc::unbounded_channel();
+
+        assert!(
+            self.0.open.set(open_tx).is_ok(),
+            "mux session can be started only once"
+        );
+        lock_ignore_poison(&self.0.routes).fallback = Some(fallback_tx);
+
+        let connection = Connection::new(io.compat(), Config::default(), mode);
+        tokio::spawn(self.clone().drive(connection, open_rx));
+
+        UnboundedReceiverStream::new(fallback_rx)
+    }
+
+    /// Open a stream to the other side.
+    pub(crate) async fn open(&self) -> IoResult<BoxedIo> {
+        let (tx, rx) = oneshot::channel();
+
+        self.0
+            .open
+            .get()
+            .ok_or(ErrorKind::NotConnected)?
+            .send(tx)
+            .map_err(|_| IoError::from(ErrorKind::NotConnected))?;
+
+        rx.await
+            .map_err(|_| IoError::from(ErrorKind::ConnectionAborted))?
+    }
+
+    /// Accept the inbound streams knocked for service `id`.
+    pub(crate) fn listen(&self, id: u32) -> UnboundedReceiverStream<BoxedIo> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        lock_ignore_poison(&self.0.routes).listeners.insert(id, tx);
+        self.0.listening.notify_waiters();
+
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// Route the next inbound stream to the listener of `id`, waiting up to `wait` for it to
+    /// listen since the knock may arrive before the service is served.
+    pub(crate) async fn accept_knock(&self, id: u32, wait: Duration) -> Result<(), String> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let listening = self.0.listening.notified();
+
+            {
+                let mut routes = lock_ignore_poison(&self.0.routes);
+                if let Some(tx) = routes.listeners.get(&id).filter(|tx| !tx.is_closed()) {
+                    let tx = tx.clone();
+                    routes.knocked.push_back(tx);
+                    return Ok(());
+                }
+            }
+
+            if timeout_at(deadline, listening).await.is_err() {
+                return Err(format!("no listener for id {id}"));
+            }
+        }
+    }
+
+    fn route(&self, mut io: BoxedIo) {
+        let mut routes = lock_ignore_poison(&self.0.routes);
+
+        while let Some(tx) = routes.knocked.pop_front() {
+            match tx.send(io) {
+                Ok(()) => return,
+                // the listener is gone, try the next one
+                Err(SendError(back)) => io = back,
+            }
+        }
+
+        if let Some(tx) = &routes.fallback {
+            _ = tx.send(io);
+        }
+    }
+
+    async fn drive(
+        self,
+        mut connection: Connection<Compat<BoxedIo>>,
+        mut open_rx: UnboundedReceiver<OpenRequest>,
+    ) {
+        let mut opening: Option<OpenRequest> = None;
+
+        poll_fn(|cx| {
+            // yamux opens one stream at a time
+            loop {
+                if let Some(tx) = opening.take() {
+                    match connection.poll_new_outbound(cx) {
+                        Poll::Ready(r) => {
+                            let r = r
+                                .map(|stream| BoxedIo::new(stream.compat()))
+                                .map_err(IoError::other);
+                            _ = tx.send(r);
+                        }
+                        Poll::Pending => {
+                            opening = Some(tx);
+                            break;
+                        }
+                    }
+                }
+
+                match open_rx.poll_recv(cx) {
+                    Poll::Ready(Some(tx)) => opening = Some(tx),
+                    _ => break,
+                }
+            }
+
+            // polling inbound streams drives the whole session
+            loop {
+                match connection.poll_next_inbound(cx) {
+                    Poll::Ready(Some(Ok(stream))) => self.route(BoxedIo::new(stream.compat())),
+                    Poll::Ready(Some(Err(_)) | None) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        })
+        .await;
+
+        // end the listeners along with the session
+        let mut routes = lock_ignore_poison(&self.0.routes);
+        routes.listeners.clear();
+        routes.knocked.clear();
+        routes.fallback = None;
+    }
+}
@@ -1,19 +1,29 @@
-use std::{convert::Infallible, fs, mem, ops::RangeInclusive, path::Path};
+use std::{
+    convert::Infallible, fs, future::Future, io::Result as IoResult, mem, ops::RangeInclusive,
+    path::Path, sync::Arc,
+};
 
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
 use http::{Request, Response};
+use rustls::ServerConfig as TlsServerConfig;
 use tokio::net::{TcpListener, UnixListener};
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::{
     body::Body,
     server::NamedService,
-    service::RoutesBuilder,
+    service::{Routes, RoutesBuilder},
     transport::server::{Server as TonicServer, TcpIncoming},
 };
 use tower_service::Service;
+use yamux::Mode;
 
-use super::utils;
+use super::{io::BoxedIo, mux::Mux, tls::TlsIncoming, utils};
 use crate::{handshake::Network, PluginxError};
 
+#[derive(Clone)]
 pub(crate) enum TransportConfig {
     Unix {
         prefix: Box<str>,
@@ -26,6 +36,9 @@ pub(crate) enum TransportConfig {
 
 pub(crate) struct ServerConfig {
     pub transport_config: TransportConfig,
+    pub tls: Option<Arc<TlsServerConfig>>,
+    /// accept a single connection and serve the streams multiplexed over it
+    pub mux: Option<Mux>,
 }
 
 pub(crate) enum Transport {
@@ -37,6 +50,8 @@ pub(crate) struct Server {
     // the Option makes Drop trait available while we use moving self in run()
     transport: Option<Transport>,
     network: Network,
+    tls: Option<Arc<TlsServerConfig>>,
+    mux: Option<Mux>,
     routes_builder: RoutesBuilder,
 }
 
@@ -65,6 +80,8 @@ impl Server {
         Ok(Self {
             transport: Some(transport),
             network,
+            tls: config.tls,
+            mux: config.mux,
             routes_builder: RoutesBuilder::default(),
         })
     }
@@ -89,26 +106,72 @@ impl Server {
         self
     }
 
-    pub(crate) async fn run(mut self) -> Result<(), PluginxError> {
+    /// Serve the added services, stop accepting connections once `signal` resolves and return
+    /// after the open connections are done.
+    pub(crate) async fn run(
+        mut self,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), PluginxError> {
         let routes = mem::take(&mut self.routes_builder).routes();
+        self.serve(routes, signal).await
+    }
 
-        match self.transport.take().expect("transport is always Some") {
-            Transport::Unix(u) => {
-                TonicServer::builder()
-                    .add_routes(routes)
-                    .serve_with_incoming(UnixListenerStream::new(u))
-                    .await?
-            }
-            Transport::Tcp(t) => {
-                TonicServer::builder()
-                    .add_routes(routes)
-                    .serve_with_incoming(TcpIncoming::from(t))
-                    .await?
+    /// Serve `routes` instead of the added services, until `signal` resolves.
+    pub(crate) async fn serve(
+        mut self,
+        routes: Routes,
+        signal: impl Future<Output = ()>,
+    ) -> Result<(), PluginxError> {
+        let incoming = match self.transport.take().expect("transport is always Some") {
+            Transport::Unix(u) => UnixListenerStream::new(u).map_ok(BoxedIo::new).boxed(),
+            Transport::Tcp(t) => TcpIncoming::from(t).map_ok(BoxedIo::new).boxed(),
+        };
+        let incoming = match self.mux.take() {
+            Some(mux) => multiplexed(incoming, mux),
+            None => incoming,
+        };
+
+        serve_incoming(incoming, self.tls.take(), routes, signal).await
+    }
+}
+
+/// Serve `routes` on connections from `incoming` until `signal` resolves.
+pub(crate) async fn serve_incoming(
+    incoming: BoxStream<'static, IoResult<BoxedIo>>,
+    tls: Option<Arc<TlsServerConfig>>,
+    routes: Routes,
+    signal: impl Future<Output = ()>,
+) -> Result<(), PluginxError> {
+    let incoming = match tls {
+        Some(tls) => TlsIncoming::new(incoming, tls).boxed(),
+        None => incoming,
+    };
+
+    TonicServer::builder()
+        .add_routes(routes)
+        .serve_with_incoming_shutdown(incoming, signal)
+        .await?;
+
+    Ok(())
+}
+
+/// Like go-plugin, only the first connection is accepted when multiplexing, the yamux session
+/// over it carries everything else.
+fn multiplexed(
+    mut incoming: BoxStream<'static, IoResult<BoxedIo>>,
+    mux: Mux,
+) -> BoxStream<'static, IoResult<BoxedIo>> {
+    stream::once(async move {
+        while let Some(io) = incoming.next().await {
+            if let Ok(io) = io {
+                return mux.start(io, Mode::Server).map(Ok).boxed();
             }
         }
 
-        Ok(())
-    }
+        stream::empty().boxed()
+    })
+    .flatten()
+    .boxed()
 }
 
 impl Drop for Server {
@@ -0,0 +1,339 @@
+use std::{
+    future::Future,
+    io::Result as IoResult,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::{stream::FuturesUnordered, Stream, StreamExt};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose, PKCS_ECDSA_P521_SHA512,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        aws_lc_rs::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+    },
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, Error as TlsError,
+    ServerConfig, SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+
+use super::io::BoxedIo;
+use crate::PluginxError;
+
+/// go-plugin always uses `localhost` as the TLS server name.
+pub(crate) const SERVER_NAME: &str = "localhost";
+
+const ALPN_H2: &[u8] = b"h2";
+
+/// Ephemeral certificate used by one side of the AutoMTLS connection.
+///
+/// Mirrors go-plugin's `generateCert`: a self-signed ECDSA P-521 CA certificate for `localhost`
+/// usable for both client and server authentication.
+pub(crate) struct TlsIdentity {
+    cert: CertificateDer<'static>,
+    cert_pem: String,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl TlsIdentity {
+    pub(crate) fn generate() -> Result<Self, PluginxError> {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P521_SHA512)?;
+
+        let mut params = CertificateParams::new([SERVER_NAME.to_owned()])?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, SERVER_NAME);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "pluginx");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+            KeyUsagePurpose::KeyAgreement,
+            KeyUsagePurpose::KeyCertSign,
+        ];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ClientAuth,
+            ExtendedKeyUsagePurpose::ServerAuth,
+        ];
+
+        let cert = params.self_signed(&key)?;
+
+        Ok(Self {
+            cert_pem: cert.pem(),
+            cert: cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()),
+        })
+    }
+
+    /// PEM encoded certificate, the format of `PLUGIN_CLIENT_CERT`.
+    pub(crate) fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+}
+
+/// Mutual TLS settings of one side: its own identity and the certificate pinned for the peer.
+///
+/// Both sides may act as TLS client and TLS server (the broker dials in both directions),
+/// so both configurations are built from the same settings.
+#[derive(Clone)]
+pub(crate) struct TlsConfig {
+    cert: CertificateDer<'static>,
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    pub(crate) fn new(
+        identity: TlsIdentity,
+        peer: CertificateDer<'static>,
+    ) -> Result<Self, PluginxError> {
+        Ok(Self {
+            server: Self::build_server_config(&identity, &peer)?,
+            client: Self::build_client_config(&identity, &peer)?,
+            cert: identity.cert,
+        })
+    }
+
+    /// Pin the first certificate of a PEM bundle, as sent through `PLUGIN_CLIENT_CERT`.
+    pub(crate) fn with_peer_pem(identity: TlsIdentity, pem: &[u8]) -> Result<Self, PluginxError> {
+        let peer = CertificateDer::pem_slice_iter(pem)
+            .next()
+            .ok_or(TlsError::NoCertificatesPresented)?
+            .map_err(|_| TlsError::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        Self::new(identity, peer)
+    }
+
+    /// own certificate, DER encoded
+    pub(crate) fn cert_der(&self) -> &[u8] {
+        &self.cert
+    }
+
+    pub(crate) fn server_config(&self) -> Arc<ServerConfig> {
+        self.server.clone()
+    }
+
+    pub(crate) fn client_config(&self) -> Arc<ClientConfig> {
+        self.client.clone()
+    }
+
+    fn cert_chain(
+        identity: &TlsIdentity,
+    ) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        (
+            vec![identity.cert.clone()],
+            PrivateKeyDer::Pkcs8(identity.key.clone_key()),
+        )
+    }
+
+    fn build_server_config(
+        identity: &TlsIdentity,
+        peer: &CertificateDer<'static>,
+    ) -> Result<Arc<ServerConfig>, PluginxError> {
+        let provider = Arc::new(default_provider());
+        let (chain, key) = Self::cert_chain(identity);
+
+        let mut config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(PinnedCertVerifier::new(peer.clone(), provider)))
+            .with_single_cert(chain, key)?;
+        config.alpn_protocols = vec![ALPN_H2.to_vec()];
+
+        Ok(Arc::new(config))
+    }
+
+    fn build_client_config(
+        identity: &TlsIdentity,
+        peer: &CertificateDer<'static>,
+    ) -> Result<Arc<ClientConfig>, PluginxError> {
+        let provider = Arc::new(default_provider());
+        let (chain, key) = Self::cert_chain(identity);
+
+        let mut config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(
+                peer.clone(),
+                provider,
+            )))
+            .with_client_auth_cert(chain, key)?;
+        // grpc-go refuses TLS connections without ALPN negotiated
+        config.alpn_protocols = vec![ALPN_H2.to_vec()];
+
+        Ok(Arc::new(config))
+    }
+}
+
+/// Accepts exactly one peer certificate.
+///
+/// go-plugin certificates are self-signed CAs used as leaf certificates, which webpki rejects,
+/// and since both sides exchange the certificate out of band, pinning it is all we need anyway.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(cert: CertificateDer<'static>, provider: Arc<CryptoProvider>) -> Self {
+        Self { cert, provider }
+    }
+
+    fn verify_cert(&self, end_entity: &CertificateDer<'_>) -> Result<(), TlsError> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(())
+        } else {
+            Err(TlsError::InvalidCertificate(
+                CertificateError::UnknownIssuer,
+            ))
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        self.verify_cert(end_entity)
+            .map(|_| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinnedCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        // go's TLS client falls back to its only certificate when no hint matches
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        self.verify_cert(end_entity)
+            .map(|_| ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        ServerCertVerifier::verify_tls12_signature(self, message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        ServerCertVerifier::verify_tls13_signature(self, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        ServerCertVerifier::supported_verify_schemes(self)
+    }
+}
+
+type Handshake = Pin<Box<dyn Future<Output = IoResult<BoxedIo>> + Send>>;
+
+/// Performs TLS handshakes on accepted connections concurrently, so one slow peer can't stall
+/// the accept loop.
+pub(crate) struct TlsIncoming<S> {
+    incoming: Option<S>,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl<S> TlsIncoming<S> {
+    pub(crate) fn new(incoming: S, config: Arc<ServerConfig>) -> Self {
+        Self {
+            incoming: Some(incoming),
+            acceptor: TlsAcceptor::from(config),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<S> Stream for TlsIncoming<S>
+where
+    S: Stream<Item = IoResult<BoxedIo>> + Unpin,
+{
+    type Item = IoResult<BoxedIo>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        while let Some(incoming) = this.incoming.as_mut() {
+            match incoming.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(io))) => {
+                    let accept = this.acceptor.accept(io);
+                    this.handshakes
+                        .push(Box::pin(async move { accept.await.map(BoxedIo::new) }));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => this.incoming = None,
+                Poll::Pending => break,
+            }
+        }
+
+        match this.handshakes.poll_next_unpin(cx) {
+            // a failed handshake only affects its own connection, report it like an accept error
+            Poll::Ready(Some(r)) => Poll::Ready(Some(r)),
+            Poll::Ready(None) if this.incoming.is_none() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
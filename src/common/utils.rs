@@ -5,6 +5,7 @@ use std::{
     net::{Ipv4Addr, SocketAddrV4, TcpListener as StdTcpListener},
     ops::RangeInclusive,
     path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
     task::{Context, Poll},
 };
 
@@ -92,3 +93,9 @@ where
         (self.f)(req)
     }
 }
+
+/// Lock `mutex` even if a thread panicked while holding it. The mutexes of the crate guard
+/// state that every operation leaves consistent, so a poisoned one is still fine to use.
+pub(crate) fn lock_ignore_poison<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
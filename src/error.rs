@@ -1,8 +1,8 @@
-use std::io;
+use std::{io, path::PathBuf, process::ExitStatus};
 
 use thiserror::Error;
 
-use crate::handshake::HandshakeError;
+use crate::{broker::BrokerError, handshake::HandshakeError};
 
 #[derive(Error, Debug)]
 pub enum PluginxError {
@@ -12,12 +12,55 @@ pub enum PluginxError {
     TokioTask(#[from] tokio::task::JoinError),
     #[error("io error: {0}")]
     Io(#[from] io::Error),
+    #[error("tls: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("certificate generation: {0}")]
+    CertificateGeneration(#[from] rcgen::Error),
+    #[error("broker: {0}")]
+    Broker(#[from] BrokerError),
+    #[error("stdio stream: {0}")]
+    Stdio(tonic::Status),
 
     #[error("handshake failed: {error}, message: {message}")]
     Handshake {
         error: HandshakeError,
         message: String,
     },
+
+    /// the plugin executable isn't the one expected by
+    /// [`SecureConfig`](crate::client::config::SecureConfig), it was not spawned
+    #[error("checksum mismatch of {}: expected {expected}, got {actual}", .path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    /// no detached signature was found next to the plugin executable
+    #[error("missing signature {}", .path.display())]
+    MissingSignature { path: PathBuf },
+    /// the detached signature is malformed, or doesn't match the plugin executable
+    #[error("bad signature {}", .path.display())]
+    BadSignature { path: PathBuf },
+    /// the detached signature was made with a key not trusted by the
+    /// [`SecureConfig`](crate::client::config::SecureConfig)
+    #[error("signature {} made with untrusted key {key}", .path.display())]
+    UntrustedKey { path: PathBuf, key: String },
+
+    /// the [`SandboxConfig`](crate::client::config::SandboxConfig) couldn't be set up, the
+    /// plugin was not spawned
+    #[error("sandbox: {0}")]
+    Sandbox(io::Error),
+
+    /// plugin never came up, `stderr` holds the tail of what it printed before
+    #[error(
+        "plugin failed to start: {error} ({}), stderr: {stderr}",
+        .status.map_or_else(|| "unknown status".to_owned(), |s| s.to_string())
+    )]
+    Start {
+        error: HandshakeError,
+        status: Option<ExitStatus>,
+        stderr: String,
+    },
 }
 
 /// fast convert for [`HandshakeError`] that doesn't provides any message
@@ -5,6 +5,8 @@ use std::{
     str::FromStr,
 };
 
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+
 use super::HandshakeError;
 
 #[derive(Clone, Debug)]
@@ -22,14 +24,26 @@ impl Network {
             _ => Err(e),
         }
     }
+
+    /// network name as used by go, `tcp` or `unix`
+    pub fn network_type(&self) -> &'static str {
+        match self {
+            Network::Tcp(_) => "tcp",
+            Network::Unix(_) => "unix",
+        }
+    }
+
+    pub fn address(&self) -> String {
+        match self {
+            Network::Tcp(addr) => addr.to_string(),
+            Network::Unix(path) => path.display().to_string(),
+        }
+    }
 }
 
 impl Display for Network {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        match self {
-            Network::Tcp(addr) => write!(f, "tcp|{addr}",),
-            Network::Unix(path) => write!(f, "unix|{}", path.display()),
-        }
+        write!(f, "{}|{}", self.network_type(), self.address())
     }
 }
 
@@ -63,6 +77,10 @@ pub struct HandshakeMessage {
     pub app_protocol: u32,
     pub network: Network,
     pub protocol: Protocol,
+    /// DER encoded server certificate, present when the plugin serves with AutoMTLS.
+    pub server_cert: Option<Vec<u8>>,
+    /// Whether the plugin supports gRPC broker multiplexing, only sent when the host asks for it.
+    pub multiplex: Option<bool>,
 }
 
 impl HandshakeMessage {
@@ -78,19 +96,152 @@ impl HandshakeMessage {
             app_protocol: it[1].parse()?,
             network: Network::parse(it[2], it[3])?,
             protocol: it[4].parse()?,
+            server_cert: match it.get(5) {
+                Some(cert) if !cert.is_empty() => Some(
+                    STANDARD_NO_PAD
+                        .decode(cert)
+                        .map_err(|_| HandshakeError::InvalidServerCert)?,
+                ),
+                _ => None,
+            },
+            multiplex: it
+                .get(6)
+                .map(|m| parse_go_bool(m).ok_or(HandshakeError::InvalidMultiplex))
+                .transpose()?,
         })
     }
 }
 
+/// same as go's `strconv.ParseBool`
+pub(crate) fn parse_go_bool(s: &str) -> Option<bool> {
+    match s {
+        "1" | "t" | "T" | "true" | "TRUE" | "True" => Some(true),
+        "0" | "f" | "F" | "false" | "FALSE" | "False" => Some(false),
+        _ => None,
+    }
+}
+
+/// Same layout as go-plugin prints: the TLS field is always present, even if empty, while
+/// MULTIPLEX is only appended on request since old hosts split the line into at most 6 fields.
 impl Display for HandshakeMessage {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         write!(
             f,
-            "{core_protocol}|{app_protocol}|{network}|{protocol}",
+            "{core_protocol}|{app_protocol}|{network}|{protocol}|",
             core_protocol = self.core_protocol,
             app_protocol = self.app_protocol,
             network = self.network,
             protocol = self.protocol
-        )
+        )?;
+
+        if let Some(cert) = &self.server_cert {
+            f.write_str(&STANDARD_NO_PAD.encode(cert))?;
+        }
+
+        if let Some(multiplex) = self.multiplex {
+            write!(f, "|{multiplex}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// lines as printed by go-plugin
+    const LINES: &[&str] = &[
+        "1|1|unix|/tmp/plugin2614571398|grpc|",
+        "1|3|tcp|127.0.0.1:10000|grpc|",
+        "1|1|unix|/tmp/plugin2614571398|grpc|MIIBszCCAVqgAwIBAgIRAKf7",
+        "1|1|unix|/tmp/plugin2614571398|grpc||true",
+        "1|2|tcp|127.0.0.1:10000|grpc|MIIBszCCAVqgAwIBAgIRAKf7|false",
+    ];
+
+    #[test]
+    fn round_trip() {
+        for line in LINES {
+            let message = HandshakeMessage::parse(line).unwrap();
+            assert_eq!(message.to_string(), *line);
+        }
+    }
+
+    #[test]
+    fn optional_fields() {
+        let message = HandshakeMessage::parse(LINES[0]).unwrap();
+        assert!(message.server_cert.is_none());
+        assert!(message.multiplex.is_none());
+
+        let message = HandshakeMessage::parse(LINES[4]).unwrap();
+        assert_eq!(
+            message.server_cert,
+            Some(STANDARD_NO_PAD.decode("MIIBszCCAVqgAwIBAgIRAKf7").unwrap())
+        );
+        assert_eq!(message.multiplex, Some(false));
+
+        // older plugins stop after the protocol
+        let message = HandshakeMessage::parse("1|1|unix|/tmp/plugin|grpc").unwrap();
+        assert!(message.server_cert.is_none());
+        assert!(message.multiplex.is_none());
+    }
+
+    fn parse_err(line: &str) -> HandshakeError {
+        HandshakeMessage::parse(line).unwrap_err()
+    }
+
+    #[test]
+    fn malformed_fields() {
+        use HandshakeError::*;
+
+        assert!(matches!(
+            parse_err("1|1|unix|/tmp/plugin"),
+            InvalidHandshakeMessage
+        ));
+        assert!(matches!(
+            parse_err("x|1|unix|/tmp/plugin|grpc|"),
+            ParseNumberFailed(_)
+        ));
+        assert!(matches!(
+            parse_err("1|-1|unix|/tmp/plugin|grpc|"),
+            ParseNumberFailed(_)
+        ));
+        assert!(matches!(
+            parse_err("1|1|udp|127.0.0.1:1|grpc|"),
+            InvalidNetwork
+        ));
+        assert!(matches!(
+            parse_err("1|1|tcp|localhost|grpc|"),
+            InvalidNetwork
+        ));
+        assert!(matches!(
+            parse_err("1|1|unix|/tmp/plugin|netrpc|"),
+            InvalidTransportProtocol
+        ));
+        assert!(matches!(
+            parse_err("1|1|unix|/tmp/plugin|grpc|not base64!"),
+            InvalidServerCert
+        ));
+        // go-plugin encodes without padding
+        assert!(matches!(
+            parse_err("1|1|unix|/tmp/plugin|grpc|MIIB=="),
+            InvalidServerCert
+        ));
+        assert!(matches!(
+            parse_err("1|1|unix|/tmp/plugin|grpc||yes"),
+            InvalidMultiplex
+        ));
+    }
+
+    #[test]
+    fn go_bool() {
+        for s in ["1", "t", "T", "true", "TRUE", "True"] {
+            assert_eq!(parse_go_bool(s), Some(true));
+        }
+        for s in ["0", "f", "F", "false", "FALSE", "False"] {
+            assert_eq!(parse_go_bool(s), Some(false));
+        }
+        assert_eq!(parse_go_bool("tRUE"), None);
+        assert_eq!(parse_go_bool(""), None);
     }
 }
@@ -1,7 +1,7 @@
 pub mod config;
 pub mod message;
 
-use std::num::ParseIntError;
+use std::{num::ParseIntError, time::Duration};
 
 use thiserror::Error;
 
@@ -10,16 +10,28 @@ pub use self::{
     message::{HandshakeMessage, Network, Protocol},
 };
 
+/// The only core protocol version of go-plugin.
+pub const CORE_PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Error, Debug)]
 pub enum HandshakeError {
     #[error("invalid handshake message")]
     InvalidHandshakeMessage,
 
-    #[error("unsupported core protocol version")]
-    UnsupportedCoreProtocolVersion,
+    #[error("handshake message exceeds {0} bytes")]
+    HandshakeMessageTooLong(usize),
+
+    #[error("timeout after {0:?} waiting for handshake")]
+    Timeout(Duration),
+
+    #[error("plugin exited before handshake")]
+    PluginExited,
+
+    #[error("unsupported core protocol version {received}, expected {expected}")]
+    UnsupportedCoreProtocolVersion { expected: u32, received: u32 },
 
-    #[error("unsupported protocol version")]
-    UnsupportedAppProtocolVersion,
+    #[error("unsupported app protocol version {received}, expected one of {expected:?}")]
+    UnsupportedAppProtocolVersion { expected: Vec<u32>, received: u32 },
 
     #[error("invalid handshake network type")]
     InvalidNetwork,
@@ -27,6 +39,18 @@ pub enum HandshakeError {
     #[error("invalid transport protocol")]
     InvalidTransportProtocol,
 
+    #[error("invalid server certificate")]
+    InvalidServerCert,
+
+    #[error("invalid gRPC broker multiplexing flag")]
+    InvalidMultiplex,
+
+    #[error("plugin didn't provide a server certificate for AutoMTLS")]
+    MissingServerCert,
+
+    #[error("plugin doesn't support gRPC broker multiplexing")]
+    MultiplexNotSupported,
+
     #[error("parse number failed: {0}")]
     ParseNumberFailed(#[from] ParseIntError),
 }
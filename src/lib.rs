@@ -9,7 +9,9 @@ pub mod plugin;
 pub mod proto;
 pub mod server;
 
-pub use tonic::{async_trait, server::NamedService, Request, Response, Status, Streaming};
+pub use tonic::{
+    async_trait, server::NamedService, service::Routes, Request, Response, Status, Streaming,
+};
 
 pub use self::error::PluginxError;
 
@@ -1,30 +1,94 @@
-use tokio_stream::Pending;
-use tonic::{Request, Response, Status, Streaming};
+use std::sync::Mutex;
 
-use crate::proto::{
-    grpc_broker_server::{GrpcBroker, GrpcBrokerServer},
-    ConnInfo,
+use futures_util::{stream::BoxStream, StreamExt};
+use tokio::sync::mpsc::Receiver;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Channel, Request, Response, Status, Streaming};
+
+use crate::{
+    broker::Broker,
+    common::utils::lock_ignore_poison,
+    proto::{
+        grpc_broker_client::GrpcBrokerClient,
+        grpc_broker_server::{GrpcBroker, GrpcBrokerServer},
+        ConnInfo,
+    },
 };
 
-#[derive(Clone, Debug)]
-pub struct BrokerHandler;
+/// plugin side of the [`Broker`]
+pub type BrokerHandler = Broker;
 
-pub struct BrokerServer;
+pub struct BrokerServer {
+    broker: Broker,
+    outgoing: Mutex<Option<Receiver<ConnInfo>>>,
+}
 
 impl BrokerServer {
-    pub fn new() -> (GrpcBrokerServer<Self>, BrokerHandler) {
-        (GrpcBrokerServer::new(Self), BrokerHandler)
+    pub(crate) fn new(
+        broker: Broker,
+        outgoing: Receiver<ConnInfo>,
+    ) -> (GrpcBrokerServer<Self>, BrokerHandler) {
+        (
+            GrpcBrokerServer::new(Self {
+                broker: broker.clone(),
+                outgoing: Mutex::new(Some(outgoing)),
+            }),
+            broker,
+        )
     }
 }
 
 #[tonic::async_trait]
 impl GrpcBroker for BrokerServer {
-    type StartStreamStream = Pending<Result<ConnInfo, Status>>;
+    type StartStreamStream = BoxStream<'static, Result<ConnInfo, Status>>;
 
     async fn start_stream(
         &self,
-        _: Request<Streaming<ConnInfo>>,
+        request: Request<Streaming<ConnInfo>>,
     ) -> Result<Response<Self::StartStreamStream>, Status> {
-        Ok(Response::new(tokio_stream::pending()))
+        let outgoing = lock_ignore_poison(&self.outgoing)
+            .take()
+            .ok_or_else(|| Status::unavailable("broker stream is already in use"))?;
+
+        let broker = self.broker.clone();
+        let incoming = request.into_inner();
+        tokio::spawn(async move { broker.receive(incoming).await });
+
+        // ends along with the broker, so the stream doesn't hold the server up while it drains
+        let closed = self.broker.closed();
+        Ok(Response::new(
+            ReceiverStream::new(outgoing)
+                .take_until(closed)
+                .map(Ok)
+                .boxed(),
+        ))
+    }
+}
+
+/// host side of the broker stream
+pub struct BrokerClient {
+    client: GrpcBrokerClient<Channel>,
+}
+
+impl BrokerClient {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: GrpcBrokerClient::new(channel),
+        }
+    }
+
+    /// Open the broker stream in background, it lives until either side closes it.
+    pub(crate) fn start(mut self, broker: Broker, outgoing: Receiver<ConnInfo>) {
+        // grpc-go only answers the stream along with its first message, don't block on it
+        tokio::spawn(async move {
+            match self
+                .client
+                .start_stream(ReceiverStream::new(outgoing))
+                .await
+            {
+                Ok(incoming) => broker.receive(incoming.into_inner()).await,
+                Err(_) => broker.close(),
+            }
+        });
     }
 }
@@ -19,14 +19,30 @@ impl ControllerExitSignal {
     pub async fn wait(&self) {
         let (notify, is_exit) = self.0.as_ref();
 
+        // registered before checking the flag, or a notification in between would be missed
+        let notified = notify.notified();
+
         if is_exit.load(Ordering::Acquire) {
             return;
         }
 
-        notify.notified().await;
+        notified.await;
+    }
+
+    /// Exit as if the host asked for it.
+    pub(crate) fn trigger(&self) {
+        exit(&self.0);
     }
 }
 
+fn exit(signal: &(Notify, AtomicBool)) {
+    let (notify, is_exit) = signal;
+
+    is_exit.store(true, Ordering::Release);
+
+    notify.notify_waiters();
+}
+
 impl Clone for ControllerExitSignal {
     fn clone(&self) -> Self {
         Self(self.0.clone())
@@ -49,16 +65,13 @@ impl ControllerServer {
 #[tonic::async_trait]
 impl GrpcController for ControllerServer {
     async fn shutdown(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
-        let (notify, is_exit) = self.0.as_ref();
-
-        is_exit.store(true, Ordering::Release);
-
-        notify.notify_waiters();
+        exit(&self.0);
 
         Ok(Response::new(Empty {}))
     }
 }
 
+#[derive(Clone)]
 pub struct ControllerClient {
     client: GrpcControllerClient<Channel>,
 }
@@ -1,7 +1,14 @@
 mod broker;
 mod controller;
 mod stdio;
+mod writer;
 
-pub use broker::{BrokerHandler, BrokerServer};
+pub use broker::{BrokerClient, BrokerHandler, BrokerServer};
 pub use controller::{ControllerClient, ControllerExitSignal, ControllerServer};
-pub use stdio::{StdioClient, StdioHandler, StdioServer, StdioType};
+pub use stdio::{
+    StdioBufferConfig, StdioClient, StdioHandler, StdioOverflow, StdioServer, StdioType,
+    TryWriteError,
+};
+#[cfg(feature = "hclog")]
+pub(crate) use writer::LineBuffer;
+pub use writer::{AsyncStdioWriter, StdioWriter};
@@ -1,69 +1,403 @@
-use std::sync::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
 
-use tokio::sync::mpsc::{self, error::SendError, Receiver, Sender};
-use tokio_stream::wrappers::ReceiverStream;
+use futures_util::{stream::BoxStream, StreamExt};
+use thiserror::Error;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    select,
+    sync::Notify,
+    task::block_in_place,
+};
 use tonic::{transport::Channel, Request, Response, Status, Streaming};
 
-use crate::proto::{
-    grpc_stdio_client::GrpcStdioClient,
-    grpc_stdio_server::{GrpcStdio, GrpcStdioServer},
-    StdioData,
+use super::{
+    writer::{AsyncStdioWriter, StdioWriter},
+    ControllerExitSignal,
+};
+use crate::{
+    common::utils::lock_ignore_poison,
+    proto::{
+        grpc_stdio_client::GrpcStdioClient,
+        grpc_stdio_server::{GrpcStdio, GrpcStdioServer},
+        StdioData,
+    },
 };
 
-#[derive(Clone, Debug)]
-pub struct StdioHandler {
-    tx: Sender<Result<StdioData, Status>>,
-}
+/// Writes output to the host through the stdio stream, buffered until the host reads it.
+#[derive(Clone)]
+pub struct StdioHandler(Arc<Shared>);
 
+#[derive(Clone, Copy, Debug)]
 pub enum StdioType {
     Stdout = 1,
     Stderr = 2,
 }
 
+/// Output waiting for the host.
+#[derive(Clone, Copy, Debug)]
+pub struct StdioBufferConfig {
+    /// Bytes buffered at most. A single write larger than it is still accepted once the buffer
+    /// is empty.
+    pub capacity: usize,
+    pub overflow: StdioOverflow,
+}
+
+/// What a write does when the buffer is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StdioOverflow {
+    /// wait for the host to read, [`StdioHandler::try_write`] fails instead
+    #[default]
+    Block,
+    /// drop the oldest buffered output to make room
+    DropOldest,
+    /// drop the output being written
+    DropNewest,
+}
+
+#[derive(Error, Debug)]
+pub enum TryWriteError {
+    #[error("stdio buffer is full")]
+    Full(Vec<u8>),
+
+    #[error("stdio stream is closed")]
+    Closed(Vec<u8>),
+}
+
+struct Shared {
+    config: StdioBufferConfig,
+    queue: Mutex<Queue>,
+    /// notified when output is buffered
+    readable: Notify,
+    /// notified when output is taken or the stream is closed
+    writable: Notify,
+    dropped_stdout: AtomicU64,
+    dropped_stderr: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queue {
+    chunks: VecDeque<StdioData>,
+    len: usize,
+    closed: bool,
+}
+
+impl Default for StdioBufferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64 * 1024,
+            overflow: StdioOverflow::Block,
+        }
+    }
+}
+
 impl StdioHandler {
-    pub async fn write(&self, out_type: StdioType, data: Vec<u8>) -> Result<(), Vec<u8>> {
-        let data = StdioData {
+    /// Buffer `data` for the host, waiting for room if the overflow policy is
+    /// [`StdioOverflow::Block`]. Fails with the data once the server is gone.
+    ///
+    /// Output is kept while the host isn't reading, including between a broken stream and the
+    /// host reopening it.
+    pub async fn write(&self, out_type: StdioType, mut data: Vec<u8>) -> Result<(), Vec<u8>> {
+        loop {
+            // registered before trying, or a read in between would be missed
+            let writable = self.0.writable.notified();
+
+            match self.try_write(out_type, data) {
+                Ok(()) => return Ok(()),
+                Err(TryWriteError::Full(back)) => data = back,
+                Err(TryWriteError::Closed(back)) => return Err(back),
+            }
+
+            writable.await;
+        }
+    }
+
+    /// [`StdioHandler::write`] for synchronous code, blocking the thread while waiting for room.
+    ///
+    /// On a multi-thread runtime, the other workers keep running meanwhile. A current_thread
+    /// runtime would never read the buffer while blocked, so there a full buffer drops the
+    /// output instead, counted in [`StdioHandler::dropped_bytes`].
+    pub fn blocking_write(&self, out_type: StdioType, data: Vec<u8>) -> Result<(), Vec<u8>> {
+        match Handle::try_current().map(|runtime| runtime.runtime_flavor()) {
+            Ok(RuntimeFlavor::MultiThread) => {
+                block_in_place(|| block_on(self.write(out_type, data)))
+            }
+            Ok(_) => match self.try_write(out_type, data) {
+                Err(TryWriteError::Full(data)) => {
+                    self.0.count_dropped(out_type as i32, data.len());
+                    Ok(())
+                }
+                Err(TryWriteError::Closed(data)) => Err(data),
+                Ok(()) => Ok(()),
+            },
+            Err(_) => block_on(self.write(out_type, data)),
+        }
+    }
+
+    /// Buffer `data` for the host without waiting, dropping output according to the overflow
+    /// policy. Fails with [`TryWriteError::Full`] only with [`StdioOverflow::Block`].
+    pub fn try_write(&self, out_type: StdioType, data: Vec<u8>) -> Result<(), TryWriteError> {
+        let StdioBufferConfig { capacity, overflow } = self.0.config;
+        let mut queue = lock_ignore_poison(&self.0.queue);
+
+        if queue.closed {
+            return Err(TryWriteError::Closed(data));
+        }
+
+        if !queue.fits(data.len(), capacity) {
+            match overflow {
+                StdioOverflow::Block => return Err(TryWriteError::Full(data)),
+                StdioOverflow::DropNewest => {
+                    self.0.count_dropped(out_type as i32, data.len());
+                    return Ok(());
+                }
+                StdioOverflow::DropOldest => {
+                    while !queue.fits(data.len(), capacity) {
+                        let oldest = queue.pop().expect("output doesn't fit a non-empty buffer");
+                        self.0.count_dropped(oldest.channel, oldest.data.len());
+                    }
+                }
+            }
+        }
+
+        queue.push(StdioData {
             channel: out_type as i32,
             data,
-        };
+        });
+        drop(queue);
+
+        self.0.readable.notify_one();
+        Ok(())
+    }
+
+    /// bytes of `out_type` dropped so far because the buffer was full
+    pub fn dropped_bytes(&self, out_type: StdioType) -> u64 {
+        self.0.dropped(out_type as i32).load(Ordering::Relaxed)
+    }
+
+    /// line buffered [`std::io::Write`] to `out_type`, e.g. for a logger
+    pub fn writer(&self, out_type: StdioType) -> StdioWriter {
+        StdioWriter::new(self.clone(), out_type)
+    }
+
+    /// line buffered [`tokio::io::AsyncWrite`] to `out_type`
+    pub fn async_writer(&self, out_type: StdioType) -> AsyncStdioWriter {
+        AsyncStdioWriter::new(self.clone(), out_type)
+    }
+}
+
+/// Run `future` on the current thread, parking it while pending.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+impl StdioHandler {
+    /// A handler without a server, nothing reads its buffer but [`StdioHandler::take_buffered`].
+    pub(crate) fn detached(config: StdioBufferConfig) -> Self {
+        Self(Shared::new(config))
+    }
+
+    pub(crate) fn take_buffered(&self) -> Vec<StdioData> {
+        let mut queue = lock_ignore_poison(&self.0.queue);
+        let buffered = queue.chunks.drain(..).collect();
+        queue.len = 0;
+        drop(queue);
+
+        self.0.writable.notify_waiters();
+        buffered
+    }
+}
+
+impl Debug for StdioHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("StdioHandler")
+            .field("config", &self.0.config)
+            .finish_non_exhaustive()
+    }
+}
 
-        self.tx
-            .send(Ok(data))
-            .await
-            .map_err(|SendError(r)| r.expect("take back the Ok inner data").data)
+impl Shared {
+    fn dropped(&self, channel: i32) -> &AtomicU64 {
+        if channel == StdioType::Stderr as i32 {
+            &self.dropped_stderr
+        } else {
+            &self.dropped_stdout
+        }
+    }
+
+    fn count_dropped(&self, channel: i32, len: usize) {
+        self.dropped(channel)
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn new(config: StdioBufferConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            queue: Mutex::default(),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            dropped_stdout: AtomicU64::new(0),
+            dropped_stderr: AtomicU64::new(0),
+        })
     }
 }
 
-pub struct StdioServer(Mutex<Option<Receiver<Result<StdioData, Status>>>>);
+impl Queue {
+    fn fits(&self, len: usize, capacity: usize) -> bool {
+        self.chunks.is_empty() || self.len + len <= capacity
+    }
+
+    fn push(&mut self, data: StdioData) {
+        self.len += data.data.len();
+        self.chunks.push_back(data);
+    }
+
+    fn pop(&mut self) -> Option<StdioData> {
+        let data = self.chunks.pop_front()?;
+        self.len -= data.data.len();
+        Some(data)
+    }
+}
+
+/// Reading side of the buffer, closing it when dropped along with the server.
+struct StdioReceiver(Arc<Shared>);
+
+impl StdioReceiver {
+    async fn recv(&self) -> StdioData {
+        loop {
+            if let Some(data) = self.try_recv() {
+                return data;
+            }
+
+            // a permit is kept if output arrives before waiting
+            self.0.readable.notified().await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<StdioData> {
+        let data = lock_ignore_poison(&self.0.queue).pop()?;
+        self.0.writable.notify_waiters();
+        Some(data)
+    }
+}
+
+impl Drop for StdioReceiver {
+    fn drop(&mut self) {
+        let mut queue = lock_ignore_poison(&self.0.queue);
+        queue.closed = true;
+        queue.chunks.clear();
+        queue.len = 0;
+        drop(queue);
+
+        self.0.writable.notify_waiters();
+    }
+}
+
+/// Holds the receiver while no stream is open.
+type Slot = Mutex<Option<StdioReceiver>>;
+
+pub struct StdioServer {
+    slot: Arc<Slot>,
+    exit_signal: ControllerExitSignal,
+}
+
+/// The receiver lent to an open stream, given back once the stream is dropped, so the host can
+/// reopen the stream after losing it.
+struct Lease {
+    receiver: Option<StdioReceiver>,
+    slot: Weak<Slot>,
+}
+
+impl Lease {
+    fn receiver(&self) -> &StdioReceiver {
+        self.receiver.as_ref().expect("taken only on drop")
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // dropping the receiver closes the buffer once the server is gone
+        if let Some(slot) = self.slot.upgrade() {
+            *lock_ignore_poison(&slot) = self.receiver.take();
+        }
+    }
+}
 
 impl StdioServer {
-    pub fn new() -> (GrpcStdioServer<Self>, StdioHandler) {
-        let (tx, rx) = mpsc::channel(1);
+    /// The stream ends once `exit_signal` fires and the buffered output is sent, so it doesn't
+    /// hold the server up while it drains.
+    pub fn new(
+        config: StdioBufferConfig,
+        exit_signal: ControllerExitSignal,
+    ) -> (GrpcStdioServer<Self>, StdioHandler) {
+        let shared = Shared::new(config);
 
         (
-            GrpcStdioServer::new(Self(Mutex::new(Some(rx)))),
-            StdioHandler { tx },
+            GrpcStdioServer::new(Self {
+                slot: Arc::new(Mutex::new(Some(StdioReceiver(shared.clone())))),
+                exit_signal,
+            }),
+            StdioHandler(shared),
         )
     }
 }
 
 #[tonic::async_trait]
 impl GrpcStdio for StdioServer {
-    type StreamStdioStream = ReceiverStream<Result<StdioData, Status>>;
+    type StreamStdioStream = BoxStream<'static, Result<StdioData, Status>>;
 
     async fn stream_stdio(
         &self,
         _: Request<()>,
     ) -> Result<Response<Self::StreamStdioStream>, Status> {
-        let receiver = match self.0.try_lock() {
-            Ok(mut rx) => match rx.take() {
-                Some(rx) => Ok(rx),
-                None => Err(Status::unavailable("stdio stream is already in use")),
+        let receiver = lock_ignore_poison(&self.slot)
+            .take()
+            .ok_or_else(|| Status::unavailable("stdio stream is already in use"))?;
+        let lease = Lease {
+            receiver: Some(receiver),
+            slot: Arc::downgrade(&self.slot),
+        };
+
+        let stream = futures_util::stream::unfold(
+            (lease, self.exit_signal.clone()),
+            |(lease, exit_signal)| async move {
+                let data = select! {
+                    biased;
+                    data = lease.receiver().recv() => data,
+                    _ = exit_signal.wait() => lease.receiver().try_recv()?,
+                };
+                Some((Ok(data), (lease, exit_signal)))
             },
-            Err(_) => Err(Status::internal("mutex is poisoned")),
-        }?;
+        );
 
-        Ok(Response::new(ReceiverStream::new(receiver)))
+        Ok(Response::new(stream.boxed()))
     }
 }
 
@@ -82,3 +416,199 @@ impl StdioClient {
         Ok(self.client.stream_stdio(()).await?.into_inner())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    fn full_handler(overflow: StdioOverflow) -> StdioHandler {
+        let handler = StdioHandler::detached(StdioBufferConfig {
+            capacity: 4,
+            overflow,
+        });
+        handler
+            .try_write(StdioType::Stdout, b"full".to_vec())
+            .unwrap();
+        handler
+    }
+
+    /// Take the buffered output from another thread once `delay` elapsed.
+    fn read_later(handler: &StdioHandler, delay: Duration) -> thread::JoinHandle<Vec<StdioData>> {
+        let handler = handler.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            handler.take_buffered()
+        })
+    }
+
+    #[test]
+    fn blocking_write_without_runtime_waits() {
+        let handler = full_handler(StdioOverflow::Block);
+        let reader = read_later(&handler, Duration::from_millis(50));
+
+        handler
+            .blocking_write(StdioType::Stdout, b"next".to_vec())
+            .unwrap();
+        assert_eq!(reader.join().unwrap()[0].data, b"full");
+        assert_eq!(handler.take_buffered()[0].data, b"next");
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+    }
+
+    #[test]
+    fn blocking_write_on_multi_thread_runtime_waits() {
+        let handler = full_handler(StdioOverflow::Block);
+        let runtime = Builder::new_multi_thread().build().unwrap();
+
+        runtime.block_on(async {
+            let reader = {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    tokio::task::yield_now().await;
+                    handler.take_buffered()
+                })
+            };
+            let writer = {
+                let handler = handler.clone();
+                tokio::spawn(
+                    async move { handler.blocking_write(StdioType::Stdout, b"next".to_vec()) },
+                )
+            };
+
+            writer.await.unwrap().unwrap();
+            assert_eq!(reader.await.unwrap()[0].data, b"full");
+        });
+        assert_eq!(handler.take_buffered()[0].data, b"next");
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+    }
+
+    #[test]
+    fn blocking_write_on_current_thread_runtime_drops() {
+        let handler = full_handler(StdioOverflow::Block);
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        // parking would deadlock, nothing else runs on this runtime to read the buffer
+        runtime.block_on(async {
+            handler
+                .blocking_write(StdioType::Stdout, b"next".to_vec())
+                .unwrap();
+        });
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 4);
+        assert_eq!(handler.take_buffered()[0].data, b"full");
+    }
+
+    fn handler(capacity: usize, overflow: StdioOverflow) -> StdioHandler {
+        StdioHandler::detached(StdioBufferConfig { capacity, overflow })
+    }
+
+    /// Fill a buffer of 8 bytes with stderr then stdout output.
+    fn filled(overflow: StdioOverflow) -> StdioHandler {
+        let handler = handler(8, overflow);
+        handler
+            .try_write(StdioType::Stderr, b"errr".to_vec())
+            .unwrap();
+        handler
+            .try_write(StdioType::Stdout, b"outt".to_vec())
+            .unwrap();
+        handler
+    }
+
+    fn buffered(handler: &StdioHandler) -> Vec<(i32, Vec<u8>)> {
+        handler
+            .take_buffered()
+            .into_iter()
+            .map(|chunk| (chunk.channel, chunk.data))
+            .collect()
+    }
+
+    const OUT: i32 = StdioType::Stdout as i32;
+    const ERR: i32 = StdioType::Stderr as i32;
+
+    #[test]
+    fn block_keeps_everything() {
+        let handler = filled(StdioOverflow::Block);
+
+        let Err(TryWriteError::Full(back)) = handler.try_write(StdioType::Stdout, b"new".to_vec())
+        else {
+            panic!("written to a full buffer");
+        };
+        assert_eq!(back, b"new");
+        assert_eq!(
+            buffered(&handler),
+            [(ERR, b"errr".to_vec()), (OUT, b"outt".to_vec())]
+        );
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+        assert_eq!(handler.dropped_bytes(StdioType::Stderr), 0);
+    }
+
+    #[test]
+    fn drop_newest_drops_the_write() {
+        let handler = filled(StdioOverflow::DropNewest);
+
+        handler
+            .try_write(StdioType::Stdout, b"new".to_vec())
+            .unwrap();
+        handler
+            .try_write(StdioType::Stderr, b"ne".to_vec())
+            .unwrap();
+        assert_eq!(
+            buffered(&handler),
+            [(ERR, b"errr".to_vec()), (OUT, b"outt".to_vec())]
+        );
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 3);
+        assert_eq!(handler.dropped_bytes(StdioType::Stderr), 2);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let handler = filled(StdioOverflow::DropOldest);
+
+        // the stderr chunk is enough room
+        handler
+            .try_write(StdioType::Stdout, b"new".to_vec())
+            .unwrap();
+        assert_eq!(handler.dropped_bytes(StdioType::Stderr), 4);
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+
+        // both remaining chunks go
+        handler
+            .try_write(StdioType::Stdout, b"newest".to_vec())
+            .unwrap();
+        assert_eq!(buffered(&handler), [(OUT, b"newest".to_vec())]);
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 7);
+        assert_eq!(handler.dropped_bytes(StdioType::Stderr), 4);
+    }
+
+    #[test]
+    fn oversized_write_fills_an_empty_buffer() {
+        for overflow in [
+            StdioOverflow::Block,
+            StdioOverflow::DropOldest,
+            StdioOverflow::DropNewest,
+        ] {
+            let handler = handler(4, overflow);
+            handler
+                .try_write(StdioType::Stdout, b"too long".to_vec())
+                .unwrap();
+            assert_eq!(buffered(&handler), [(OUT, b"too long".to_vec())]);
+            assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+        }
+    }
+
+    #[test]
+    fn closed_buffer_hands_the_data_back() {
+        let handler = handler(8, StdioOverflow::DropNewest);
+        drop(StdioReceiver(handler.0.clone()));
+
+        let Err(TryWriteError::Closed(back)) =
+            handler.try_write(StdioType::Stdout, b"late".to_vec())
+        else {
+            panic!("written to a closed buffer");
+        };
+        assert_eq!(back, b"late");
+        assert_eq!(handler.dropped_bytes(StdioType::Stdout), 0);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    io::{self, ErrorKind, Write},
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::io::AsyncWrite;
+
+use super::{StdioHandler, StdioType};
+
+/// Output without a newline is sent anyway once this long.
+const LINE_LIMIT: usize = 8 * 1024;
+
+/// Output not ended by a newline yet, also used by the host to read plugin stderr.
+#[derive(Default)]
+pub(crate) struct LineBuffer(Vec<u8>);
+
+impl LineBuffer {
+    /// Append `data`, returning the complete lines to send, or everything past the limit.
+    pub(crate) fn push(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let searched = self.0.len();
+        self.0.extend_from_slice(data);
+
+        let end = match self.0[searched..].iter().rposition(|&b| b == b'\n') {
+            Some(i) => searched + i + 1,
+            None if self.0.len() >= LINE_LIMIT => self.0.len(),
+            None => return None,
+        };
+
+        let rest = self.0.split_off(end);
+        Some(mem::replace(&mut self.0, rest))
+    }
+
+    fn take(&mut self) -> Option<Vec<u8>> {
+        (!self.0.is_empty()).then(|| mem::take(&mut self.0))
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "stdio stream is closed")
+}
+
+/// Blocking, line buffered writer to the stdio stream, from [`StdioHandler::writer`].
+///
+/// Complete lines are sent as soon as they are written, the rest on flush or drop. Writes wait,
+/// or drop output on a current_thread runtime, like [`StdioHandler::blocking_write`].
+pub struct StdioWriter {
+    handler: StdioHandler,
+    out_type: StdioType,
+    lines: LineBuffer,
+}
+
+impl StdioWriter {
+    pub(crate) fn new(handler: StdioHandler, out_type: StdioType) -> Self {
+        Self {
+            handler,
+            out_type,
+            lines: LineBuffer::default(),
+        }
+    }
+
+    fn send(&self, data: Vec<u8>) -> io::Result<()> {
+        self.handler
+            .blocking_write(self.out_type, data)
+            .map_err(|_| closed())
+    }
+}
+
+impl Write for StdioWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(lines) = self.lines.push(buf) {
+            self.send(lines)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.lines.take() {
+            Some(rest) => self.send(rest),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for StdioWriter {
+    fn drop(&mut self) {
+        _ = self.flush();
+    }
+}
+
+/// Async, line buffered writer to the stdio stream, from [`StdioHandler::async_writer`].
+///
+/// Complete lines are sent as soon as they are written. Like a `BufWriter`, it must be flushed
+/// or shut down before being dropped, or the partial line and output waiting for room in a
+/// full buffer are lost.
+pub struct AsyncStdioWriter {
+    handler: StdioHandler,
+    out_type: StdioType,
+    lines: LineBuffer,
+    /// output waiting for room in the buffer
+    sending: Option<BoxFuture<'static, Result<(), Vec<u8>>>>,
+}
+
+impl AsyncStdioWriter {
+    pub(crate) fn new(handler: StdioHandler, out_type: StdioType) -> Self {
+        Self {
+            handler,
+            out_type,
+            lines: LineBuffer::default(),
+            sending: None,
+        }
+    }
+
+    /// Start sending `data`, it's sent right away unless the buffer is full.
+    fn send(&mut self, data: Vec<u8>, cx: &mut Context<'_>) -> io::Result<()> {
+        let (handler, out_type) = (self.handler.clone(), self.out_type);
+        self.sending = Some(async move { handler.write(out_type, data).await }.boxed());
+
+        match self.poll_sending(cx) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    fn poll_sending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(sending) = &mut self.sending else {
+            return Poll::Ready(Ok(()));
+        };
+
+        let r = ready!(sending.poll_unpin(cx));
+        self.sending = None;
+        Poll::Ready(r.map_err(|_| closed()))
+    }
+}
+
+impl AsyncWrite for AsyncStdioWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+
+        if let Some(lines) = this.lines.push(buf) {
+            this.send(lines, cx)?;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_sending(cx))?;
+
+        match this.lines.take() {
+            Some(rest) => {
+                this.send(rest, cx)?;
+                this.poll_sending(cx)
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_lines_are_split_off() {
+        let mut lines = LineBuffer::default();
+
+        assert_eq!(lines.push(b"first"), None);
+        assert_eq!(
+            lines.push(b" line\nsecond\nthi").unwrap(),
+            b"first line\nsecond\n"
+        );
+        assert_eq!(lines.take().unwrap(), b"thi");
+        assert_eq!(lines.take(), None);
+    }
+
+    #[test]
+    fn unterminated_output_is_bounded() {
+        let mut lines = LineBuffer::default();
+        let chunk = [b'x'; 3000];
+
+        let flushed: Vec<_> = (0..10).filter_map(|_| lines.push(&chunk)).collect();
+        assert_eq!(flushed.len(), 3);
+        assert!(flushed.iter().all(|line| line.len() == 9000));
+        assert_eq!(lines.take().unwrap().len(), 3000);
+    }
+}
@@ -1,13 +1,55 @@
 use std::{convert::Infallible, future::Future};
 
+use futures_util::future::BoxFuture;
 use http::{Request, Response};
 use tonic::{body::Body, server::NamedService, transport::Channel};
 use tower_service::Service;
 
+use crate::{broker::Broker, common::client::Client as InnerClient};
+
 pub trait PluginClient {
     type Client: Clone + Send + Sync;
 
-    fn client(&self, channel: Channel) -> impl Future<Output = Self::Client> + Send;
+    /// Build the client of the plugin service on `channel`. `broker` is there for services
+    /// exchanging extra connections, e.g. passing a callback service to the plugin.
+    fn client(&self, channel: Channel, broker: Broker)
+        -> impl Future<Output = Self::Client> + Send;
+}
+
+/// A set of [`PluginClient`]s served by one app protocol version, like go-plugin's `PluginSet`.
+#[derive(Default)]
+pub struct PluginSet(Vec<Box<dyn ErasedPluginClient>>);
+
+impl PluginSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_plugin<P: PluginClient + Send + Sync + 'static>(mut self, plugin: P) -> Self {
+        self.0.push(Box::new(plugin));
+        self
+    }
+
+    pub(crate) async fn register(&self, client: &mut InnerClient, broker: &Broker) {
+        for plugin in &self.0 {
+            plugin.register(client, broker).await;
+        }
+    }
+}
+
+/// object safe form of [`PluginClient`], so different plugins can live in one [`PluginSet`]
+trait ErasedPluginClient: Send + Sync {
+    fn register<'a>(&'a self, client: &'a mut InnerClient, broker: &Broker) -> BoxFuture<'a, ()>;
+}
+
+impl<P: PluginClient + Send + Sync + 'static> ErasedPluginClient for P {
+    fn register<'a>(&'a self, client: &'a mut InnerClient, broker: &Broker) -> BoxFuture<'a, ()> {
+        let broker = broker.clone();
+        Box::pin(async move {
+            let plugin = self.client(client.channel().clone(), broker).await;
+            client.add_service(plugin);
+        })
+    }
 }
 
 pub trait PluginServer {
@@ -18,7 +60,9 @@ pub trait PluginServer {
         + Sync
         + 'static;
 
-    fn server(&self) -> impl Future<Output = Self::Server> + Send;
+    /// Build the plugin service. `broker` is there for services exchanging extra connections,
+    /// e.g. dialing a callback service served by the host.
+    fn server(&self, broker: Broker) -> impl Future<Output = Self::Server> + Send;
 }
 
 /// for those service doesn't need broker
@@ -36,7 +80,7 @@ where
     type Server = T;
 
     #[inline]
-    async fn server(&self) -> Self::Server {
+    async fn server(&self, _: Broker) -> Self::Server {
         self.clone()
     }
 }
@@ -1,5 +1,34 @@
-use crate::handshake::HandshakeConfig;
+use std::time::Duration;
+
+use crate::{handshake::HandshakeConfig, meta_plugin::StdioBufferConfig};
 
 pub struct ServerConfig {
     pub handshake_config: HandshakeConfig<'static>,
+    /// How long in-flight RPCs may take to finish once the host asked the plugin to shut down,
+    /// long living streams are cut off after it.
+    pub grace_period: Duration,
+    /// Check the parent pid at this interval, and shut down as if the host asked for it once
+    /// the plugin got another parent, i.e. the host died.
+    pub parent_watchdog: Option<Duration>,
+    /// Once the handshake is printed, redirect stdout and stderr into pipes and forward
+    /// whatever is written there, by children as well, to the host through the stdio stream.
+    pub capture_stdio: bool,
+    /// How much output the stdio stream buffers until the host reads it, and what happens
+    /// once it's full.
+    pub stdio_buffer: StdioBufferConfig,
+}
+
+impl ServerConfig {
+    pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+    /// config with everything else set to its default
+    pub fn new(handshake_config: HandshakeConfig<'static>) -> Self {
+        Self {
+            handshake_config,
+            grace_period: Self::DEFAULT_GRACE_PERIOD,
+            parent_watchdog: None,
+            capture_stdio: false,
+            stdio_buffer: StdioBufferConfig::default(),
+        }
+    }
 }
@@ -0,0 +1,225 @@
+use std::{
+    error::Error,
+    fmt::Debug,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Record},
+    Event, Id, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use super::stdio;
+use crate::meta_plugin::{StdioHandler, StdioType};
+
+/// A [`tracing`] layer writing every event as an hclog JSON line, the format go-plugin hosts
+/// parse from the plugin stderr and log at the matching level.
+///
+/// Lines carry `@level`, `@message`, `@timestamp`, the event target as `@module`, and the
+/// fields of the event and of its spans.
+pub struct HclogLayer {
+    output: Output,
+}
+
+enum Output {
+    Stderr,
+    Stdio(StdioHandler),
+}
+
+/// Fields recorded on a span, kept in its extensions.
+struct SpanFields(Map<String, Value>);
+
+impl HclogLayer {
+    /// Write to stderr, where go-plugin hosts look for logs. That is the stderr the plugin was
+    /// started with, not the stdio stream it's redirected into by
+    /// [`ServerConfig::capture_stdio`](super::config::ServerConfig::capture_stdio).
+    pub fn stderr() -> Self {
+        Self {
+            output: Output::Stderr,
+        }
+    }
+
+    /// Write to the stdio stream as stderr. Lines are dropped rather than waited for when the
+    /// buffer is full, see [`StdioHandler::try_write`].
+    pub fn stdio(handler: StdioHandler) -> Self {
+        Self {
+            output: Output::Stdio(handler),
+        }
+    }
+
+    fn write(&self, line: Vec<u8>) {
+        match &self.output {
+            // a single write, so lines of concurrent events don't interleave
+            Output::Stderr => match stdio::original_stderr() {
+                Ok(mut stderr) => _ = stderr.write_all(&line),
+                Err(_) => _ = io::stderr().lock().write_all(&line),
+            },
+            Output::Stdio(handler) => _ = handler.try_write(StdioType::Stderr, line),
+        }
+    }
+}
+
+impl<S> Layer<S> for HclogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        if let Some(SpanFields(fields)) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut entry = Map::new();
+
+        // outer spans first, so inner ones and the event override them
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    entry.extend(fields.clone());
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut entry));
+
+        let metadata = event.metadata();
+        // go-plugin hosts expect a string, without a message as well
+        let message = match entry.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(message) => message.to_string(),
+            None => String::new(),
+        };
+        entry.insert("@message".into(), message.into());
+        entry.insert("@level".into(), level_name(*metadata.level()).into());
+        entry.insert("@timestamp".into(), timestamp(SystemTime::now()).into());
+        entry.insert("@module".into(), metadata.target().into());
+
+        if let Ok(mut line) = serde_json::to_vec(&entry) {
+            line.push(b'\n');
+            self.write(line);
+        }
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        self.0.insert(field.name().into(), value.to_string().into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::TRACE => "trace",
+        Level::DEBUG => "debug",
+        Level::INFO => "info",
+        Level::WARN => "warn",
+        _ => "error",
+    }
+}
+
+/// UTC timestamp in hclog's `2006-01-02T15:04:05.000000Z07:00` format.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // civil date from days since 1970-01-01, by Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::subscriber::with_default;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::meta_plugin::StdioBufferConfig;
+
+    /// The entries logged by `f`, as read back from the stdio buffer.
+    fn logged(f: impl FnOnce()) -> Vec<Map<String, Value>> {
+        let handler = StdioHandler::detached(StdioBufferConfig::default());
+        let subscriber = tracing_subscriber::registry().with(HclogLayer::stdio(handler.clone()));
+        with_default(subscriber, f);
+
+        handler
+            .take_buffered()
+            .into_iter()
+            .map(|chunk| serde_json::from_slice(&chunk.data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn message_is_a_string() {
+        let entries = logged(|| {
+            tracing::info!(user = "bob");
+            tracing::info!(user = "bob", "logged in");
+            tracing::info!(message = 42);
+        });
+
+        let messages: Vec<_> = entries.iter().map(|entry| &entry["@message"]).collect();
+        assert_eq!(messages, ["", "logged in", "42"]);
+        assert_eq!(entries[0]["user"], "bob");
+        assert_eq!(entries[0]["@level"], "info");
+    }
+}
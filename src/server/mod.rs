@@ -1,27 +1,53 @@
 pub mod config;
+#[cfg(feature = "hclog")]
+mod hclog;
+mod stdio;
 pub mod utils;
 
-use std::{env, process::exit};
+use std::{
+    collections::BTreeMap, env, os::unix::process::parent_id, pin::pin, process::exit,
+    time::Duration,
+};
 
 use http::Request;
 use tokio::{
     select,
     signal::unix::{signal, SignalKind},
+    time::{interval, timeout, MissedTickBehavior},
 };
 use tonic::body::Body;
 use tower_service::Service;
 
 use self::config::ServerConfig;
+#[cfg(feature = "hclog")]
+pub use self::hclog::HclogLayer;
 use crate::{
-    common::server::{Server as InnerServer, ServerConfig as InnerServerConfig},
-    handshake::{HandshakeMessage, Protocol},
+    broker::Broker,
+    common::{
+        mux::Mux,
+        server::{Server as InnerServer, ServerConfig as InnerServerConfig},
+        tls::{TlsConfig, TlsIdentity},
+    },
+    constant::PLUGIN_CLIENT_CERT,
+    handshake::{HandshakeMessage, Protocol, CORE_PROTOCOL_VERSION},
     meta_plugin,
     plugin::PluginServer,
     PluginxError, StdError,
 };
 
+/// plugin service waiting for the protocol negotiation before it gets served
+type PendingPlugin = Box<dyn FnOnce(&mut InnerServer) + Send>;
+
 pub struct Server {
     protocol_version: u32,
+    host_protocol_versions: Vec<u32>,
+    plugins: BTreeMap<u32, Vec<PendingPlugin>>,
+    server_cert: Option<Vec<u8>>,
+    multiplex: Option<bool>,
+    grace_period: Duration,
+    /// parent pid at startup and the interval to check it at
+    parent_watchdog: Option<(u32, Duration)>,
+    capture_stdio: bool,
 
     exit_signal: meta_plugin::ControllerExitSignal,
     stdio_handler: meta_plugin::StdioHandler,
@@ -34,6 +60,10 @@ impl Server {
     pub async fn new(
         ServerConfig {
             handshake_config: hc,
+            grace_period,
+            parent_watchdog,
+            capture_stdio,
+            stdio_buffer,
         }: ServerConfig,
     ) -> Result<Self, PluginxError> {
         if hc.magic_cookie_key.is_empty() || hc.magic_cookie_value.is_empty() {
@@ -60,7 +90,24 @@ load any plugins automatically."
             utils::unix_transport_config_from_env()?
         };
 
-        let mut server = InnerServer::new(InnerServerConfig { transport_config }).await?;
+        // the host asks for AutoMTLS by passing its certificate
+        let tls = match env::var_os(PLUGIN_CLIENT_CERT) {
+            Some(pem) if !pem.is_empty() => Some(TlsConfig::with_peer_pem(
+                TlsIdentity::generate()?,
+                pem.as_encoded_bytes(),
+            )?),
+            _ => None,
+        };
+
+        let multiplex = utils::multiplex_from_env();
+        let mux = (multiplex == Some(true)).then(Mux::default);
+
+        let mut server = InnerServer::new(InnerServerConfig {
+            transport_config: transport_config.clone(),
+            tls: tls.as_ref().map(TlsConfig::server_config),
+            mux: mux.clone(),
+        })
+        .await?;
 
         #[cfg(feature = "health")]
         {
@@ -75,14 +122,23 @@ load any plugins automatically."
         let (svc, exit_signal) = meta_plugin::ControllerServer::new();
         server.add_service(svc);
 
-        let (svc, stdio_handler) = meta_plugin::StdioServer::new();
+        let (svc, stdio_handler) = meta_plugin::StdioServer::new(stdio_buffer, exit_signal.clone());
         server.add_service(svc);
 
-        let (svc, broker_handler) = meta_plugin::BrokerServer::new();
+        let server_cert = tls.as_ref().map(|tls| tls.cert_der().to_vec());
+        let (broker, outgoing) = Broker::new(transport_config, tls, mux);
+        let (svc, broker_handler) = meta_plugin::BrokerServer::new(broker, outgoing);
         server.add_service(svc);
 
         Ok(Self {
             protocol_version: hc.protocol_version,
+            host_protocol_versions: utils::protocol_versions_from_env(),
+            plugins: BTreeMap::new(),
+            server_cert,
+            multiplex,
+            grace_period,
+            parent_watchdog: parent_watchdog.map(|period| (parent_id(), period)),
+            capture_stdio,
 
             exit_signal,
             stdio_handler,
@@ -104,50 +160,118 @@ load any plugins automatically."
         self.broker_handler.clone()
     }
 
+    /// add a plugin served with [`HandshakeConfig::protocol_version`](crate::handshake::HandshakeConfig::protocol_version)
     #[inline]
     pub async fn add_plugin<P: PluginServer + 'static>(&mut self, plugin: P) -> &mut Self
     where
         <P::Server as Service<Request<Body>>>::Future: Send + 'static,
         <P::Server as Service<Request<Body>>>::Error: Into<StdError> + Send,
     {
-        let plugin = plugin.server().await;
-        self.server.add_service(plugin);
+        self.add_versioned_plugin(self.protocol_version, plugin)
+            .await
+    }
+
+    /// add a plugin served only if `version` is the app protocol version negotiated with the host
+    pub async fn add_versioned_plugin<P: PluginServer + 'static>(
+        &mut self,
+        version: u32,
+        plugin: P,
+    ) -> &mut Self
+    where
+        <P::Server as Service<Request<Body>>>::Future: Send + 'static,
+        <P::Server as Service<Request<Body>>>::Error: Into<StdError> + Send,
+    {
+        let plugin = plugin.server(self.broker_handler.clone()).await;
+        self.plugins
+            .entry(version)
+            .or_default()
+            .push(Box::new(move |server: &mut InnerServer| {
+                server.add_service(plugin);
+            }));
         self
     }
 
-    pub async fn run(self) -> Result<(), PluginxError> {
+    pub async fn run(mut self) -> Result<(), PluginxError> {
         // go-plugin captures SIGINT and ignores them, relying on the
         // host process to manage the plugin lifecycle. We do the same here.
         //
-        // We also capture SIGTERM because it is commonly used to signal process.
-        //
-        // Notice that systemd like send SIGTERM to the process tree, so SIGTERM is
-        // nessesary to capture when running as a systemd service.
-
-        // if registering the signals fails, we just proceed without them.
-        let _s = (
-            signal(SignalKind::interrupt()),
-            signal(SignalKind::terminate()),
-        );
+        // if registering the signal fails, we just proceed without it.
+        let _interrupt = signal(SignalKind::interrupt());
 
         let exiter = self.exit_signal();
 
+        // SIGTERM is how the host escalates when Shutdown isn't answered, and what systemd
+        // sends to the process tree, so it exits like Shutdown does, draining first.
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            let exiter = exiter.clone();
+            tokio::spawn(async move {
+                if terminate.recv().await.is_some() {
+                    exiter.trigger();
+                }
+            });
+        }
+
+        if let Some((parent, period)) = self.parent_watchdog {
+            tokio::spawn(watch_parent(parent, period, exiter.clone()));
+        }
+
         let network = self.server.network().clone();
 
+        // only versions with plugins are offered, the default one when there are none at all
+        let protocol_version = utils::negotiate_protocol_version(
+            self.plugins.keys().copied(),
+            &self.host_protocol_versions,
+        )
+        .unwrap_or(self.protocol_version);
+        let plugins = self.plugins.remove(&protocol_version).unwrap_or_default();
+        for plugin in plugins {
+            plugin(&mut self.server);
+        }
+
         let hs = HandshakeMessage {
-            core_protocol: 1,
-            app_protocol: self.protocol_version,
+            core_protocol: CORE_PROTOCOL_VERSION,
+            app_protocol: protocol_version,
             network,
             protocol: Protocol::Grpc,
+            server_cert: self.server_cert.clone(),
+            multiplex: self.multiplex,
         };
         println!("{hs}");
 
-        // TODO: find a way to both send back GrpcController shutdown and also
-        // force close other resources (like flying streams).
-        select! {
-            biased;
-            r = self.server.run() => r,
-            _ = exiter.wait() => Ok(()),
+        if self.capture_stdio {
+            stdio::capture(&self.stdio_handler)?;
+        }
+
+        // the exit signal only stops accepting connections, so the reply of Shutdown still goes
+        // out and in-flight RPCs get the grace period to finish
+        let mut serve = pin!(self.server.run(exiter.wait()));
+        let r = select! {
+            r = &mut serve => r,
+            _ = exiter.wait() => {
+                self.broker_handler.close();
+                // the stdio and broker streams end with the exit signal, what's left are the
+                // in-flight RPCs, dropped along with the server if they overrun
+                timeout(self.grace_period, serve).await.unwrap_or(Ok(()))
+            }
+        };
+
+        self.broker_handler.close();
+
+        r
+    }
+}
+
+/// Exit once the parent isn't `parent` anymore, orphans are adopted by init or a subreaper.
+async fn watch_parent(parent: u32, period: Duration, exiter: meta_plugin::ControllerExitSignal) {
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        if parent_id() != parent {
+            exiter.trigger();
+            return;
         }
     }
 }
@@ -0,0 +1,68 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+    sync::OnceLock,
+};
+
+use tokio::{io::AsyncReadExt, net::unix::pipe::Receiver};
+
+use crate::meta_plugin::{StdioHandler, StdioType};
+
+/// A copy of fd 2 made before [`capture`] replaces it.
+static ORIGINAL_STDERR: OnceLock<File> = OnceLock::new();
+
+/// Redirect stdout and stderr into pipes forwarded through `handler`, go-plugin does the same
+/// with `os.Stdout` and `os.Stderr` once the handshake is printed.
+pub(crate) fn capture(handler: &StdioHandler) -> io::Result<()> {
+    let stdout = {
+        // nothing buffered may end up in the pipe, or written in between
+        let mut stdout = io::stdout().lock();
+        stdout.flush()?;
+        redirect(libc::STDOUT_FILENO)?
+    };
+    original_stderr()?;
+    let stderr = redirect(libc::STDERR_FILENO)?;
+
+    tokio::spawn(pump(stdout, StdioType::Stdout, handler.clone()));
+    tokio::spawn(pump(stderr, StdioType::Stderr, handler.clone()));
+
+    Ok(())
+}
+
+/// The stderr the plugin was started with, even once [`capture`] redirected fd 2 into the
+/// stdio stream.
+pub(crate) fn original_stderr() -> io::Result<&'static File> {
+    if let Some(stderr) = ORIGINAL_STDERR.get() {
+        return Ok(stderr);
+    }
+
+    let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+    Ok(ORIGINAL_STDERR.get_or_init(|| stderr.into()))
+}
+
+/// Replace `fd` with the write end of a new pipe, returning the read end.
+fn redirect(fd: RawFd) -> io::Result<Receiver> {
+    let (reader, writer) = io::pipe()?;
+
+    // SAFETY: both are valid descriptors, and `fd` is never owned by rust
+    if unsafe { libc::dup2(writer.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Receiver::from_owned_fd(OwnedFd::from(reader))
+}
+
+async fn pump(mut pipe: Receiver, out_type: StdioType, handler: StdioHandler) {
+    let mut buf = vec![0; 4096];
+
+    loop {
+        let n = match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+
+        // once the host is gone the output is discarded, so writers never block on the pipe
+        _ = handler.write(out_type, buf[..n].to_vec()).await;
+    }
+}
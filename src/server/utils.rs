@@ -2,7 +2,11 @@ use std::{env, io, path::PathBuf};
 
 use crate::{
     common::server::TransportConfig,
-    constant::{PLUGIN_MAX_PORT, PLUGIN_MIN_PORT, PLUGIN_UNIX_SOCKET_DIR},
+    constant::{
+        PLUGIN_MAX_PORT, PLUGIN_MIN_PORT, PLUGIN_MULTIPLEX_GRPC, PLUGIN_PROTOCOL_VERSIONS,
+        PLUGIN_UNIX_SOCKET_DIR,
+    },
+    handshake::message::parse_go_bool,
 };
 
 const PLUGIN_UNIX_SOCKET_PREFIX: &str = "plugin-";
@@ -19,6 +23,49 @@ pub(crate) fn tcp_transport_config_from_env() -> io::Result<TransportConfig> {
     Ok(TransportConfig::Tcp { port_range })
 }
 
+/// app protocol versions supported by the host, invalid entries are skipped like go-plugin does
+pub(crate) fn protocol_versions_from_env() -> Vec<u32> {
+    let Ok(versions) = env::var(PLUGIN_PROTOCOL_VERSIONS) else {
+        return Vec::new();
+    };
+
+    versions
+        .split(',')
+        .filter_map(|v| match v.trim().parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                eprintln!("host sent invalid plugin version {v:?}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// whether the host asks for gRPC broker multiplexing, [`None`] for hosts not aware of it
+pub(crate) fn multiplex_from_env() -> Option<bool> {
+    env::var(PLUGIN_MULTIPLEX_GRPC)
+        .ok()
+        .and_then(|v| parse_go_bool(&v))
+}
+
+/// Pick the highest version supported by both sides. If there is none, fall back to the lowest
+/// version of the plugin, which serves legacy hosts that don't send their versions.
+pub(crate) fn negotiate_protocol_version(
+    plugin_versions: impl DoubleEndedIterator<Item = u32>,
+    host_versions: &[u32],
+) -> Option<u32> {
+    let mut lowest = None;
+
+    for version in plugin_versions.rev() {
+        if host_versions.contains(&version) {
+            return Some(version);
+        }
+        lowest = Some(version);
+    }
+
+    lowest
+}
+
 pub(crate) fn unix_transport_config_from_env() -> io::Result<TransportConfig> {
     let dir = env::var(PLUGIN_UNIX_SOCKET_DIR)
         .map(PathBuf::from)